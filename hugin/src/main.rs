@@ -1,15 +1,16 @@
-use anyhow::Context;
+use anyhow::{bail, Context};
 use auth1_sdk::{Identity, KeyStore};
 use axum::{
     extract::{FromRef, Path, Query, State},
-    http::StatusCode,
+    http::{HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     routing::{delete, get},
     Extension, Json, Router,
 };
 use axum_tracing_opentelemetry::opentelemetry_tracing_layer;
+use clap::{Parser, Subcommand};
 use itertools::Itertools;
-use meilisearch_sdk::key::Action;
+use meilisearch_sdk::key::{Action, Key};
 use opentelemetry::{
     sdk::{propagation::TraceContextPropagator, trace, Resource},
     KeyValue,
@@ -20,49 +21,102 @@ use sqlx::{
     postgres::{types::PgRange, PgPoolOptions},
     PgPool,
 };
-use std::{env, net::SocketAddr, time::Duration};
-use stor::Menu;
+use std::{path::PathBuf, time::Duration};
+use stor::{
+    config::{self, Config, Required},
+    run::IndexRun,
+    Menu,
+};
 use time::Date;
-use tower_http::cors::CorsLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tracing::{error, info};
 use tracing_subscriber::{
     filter::LevelFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
 use uuid::Uuid;
 
+#[derive(Debug, Parser)]
+struct Opt {
+    /// TOML configuration file shared with munin. Settings given in the
+    /// environment take precedence.
+    #[arg(long, env = config::CONFIG_ENV)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Validate the configuration and print it without secrets
+    Check,
+}
+
+const REQUIRED: &[Required] = &[Required::Database, Required::Meili];
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
+    let opt = Opt::parse();
+    let config = Config::load(opt.config.as_deref())?;
 
-    init_telemetry(
-        env::var("OTLP_ENDPOINT").unwrap_or_else(|_| "http://localhost:4317".to_owned()),
-    )?;
+    if let Some(Command::Config(ConfigCommand::Check)) = opt.cmd {
+        return config.check(REQUIRED);
+    }
+
+    let problems = config.problems(REQUIRED);
+    if !problems.is_empty() {
+        bail!("invalid configuration: {}", problems.join("; "));
+    }
+
+    init_telemetry(&config.telemetry.otlp_endpoint)?;
 
     let pg = PgPoolOptions::new()
-        .connect(&env::var("DATABASE_URL")?)
+        .connect(config.database.url.as_deref().unwrap_or_default())
         .await
         .context("could not connect to database")?;
 
     let state = AppState {
         pg,
-        meili: meilisearch_sdk::Client::new(env::var("MEILI_URL")?, env::var("MEILI_KEY")?),
+        meili: meilisearch_sdk::Client::new(
+            config.meili.url.as_deref().unwrap_or_default(),
+            config.meili.key.as_deref().unwrap_or_default(),
+        ),
     };
 
+    // not fatal, as MeiliSearch might only be unavailable for now
+    match search_key(&state.meili).await {
+        Ok(Some(_)) => {}
+        Ok(None) => error!(
+            "no search-only MeiliSearch key covers {}, so /key will respond with 404",
+            SEARCH_INDEXES.join(" and ")
+        ),
+        Err(e) => error!("failed to list the MeiliSearch keys: {e}"),
+    }
+
     let app = Router::new()
         .route("/stats", get(stats))
         .route("/key", get(meilisearch_key))
         .route("/menus", get(menus))
         .route("/menus/:menu_id", get(menu))
         .route("/menus/:menu_id/days", get(days))
+        .route("/runs", get(runs))
         .route("/reviews", get(list_reviews).post(create_review))
         .route("/reviews/:review_id", delete(delete_review))
         .layer(opentelemetry_tracing_layer())
         .route("/health", get(health))
-        .layer(CorsLayer::permissive().max_age(Duration::from_secs(3600)))
+        .layer(cors(&config.hugin)?)
         .layer(Extension(KeyStore::default()))
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 8000));
+    let addr = config.hugin.listen;
 
     info!("listening on {addr}");
 
@@ -73,6 +127,27 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Any origin is allowed unless the allowed origins are configured.
+fn cors(config: &config::Hugin) -> anyhow::Result<CorsLayer> {
+    let cors = if config.cors_origins.is_empty() {
+        CorsLayer::permissive()
+    } else {
+        let origins = config
+            .cors_origins
+            .iter()
+            .map(|o| HeaderValue::from_str(o.trim_end_matches('/')))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins))
+            .allow_methods(Any)
+            .allow_headers(Any)
+            .expose_headers(Any)
+    };
+
+    Ok(cors.max_age(Duration::from_secs(config.cors_max_age_secs)))
+}
+
 fn init_telemetry(otlp_endpoint: impl Into<String>) -> anyhow::Result<()> {
     opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
 
@@ -232,6 +307,26 @@ async fn days(
     Ok(([("cache-control", "no-cache")], Json(days)))
 }
 
+#[derive(Debug, Deserialize)]
+struct RunsQuery {
+    #[serde(default = "default_runs_limit")]
+    limit: i64,
+}
+
+fn default_runs_limit() -> i64 {
+    30
+}
+
+/// The most recent index runs, newest first.
+async fn runs(
+    State(db): State<PgPool>,
+    Query(RunsQuery { limit }): Query<RunsQuery>,
+) -> Result<impl IntoResponse> {
+    let runs = IndexRun::latest(&mut *db.acquire().await?, limit.clamp(1, 100)).await?;
+
+    Ok(([("cache-control", "public, max-age=60")], Json(runs)))
+}
+
 #[derive(Debug, Deserialize)]
 struct ReviewQuery {
     menu: Option<Uuid>,
@@ -345,20 +440,27 @@ async fn delete_review(
     }
 }
 
+/// The MeiliSearch indexes maintained by munin, which clients search.
+const SEARCH_INDEXES: &[&str] = &["menus", "meals"];
+
+/// A search-only key that covers all of [`SEARCH_INDEXES`], if there is one.
+async fn search_key(
+    client: &meilisearch_sdk::Client,
+) -> Result<Option<Key>, meilisearch_sdk::errors::Error> {
+    Ok(client.get_keys().await?.results.into_iter().find(|k| {
+        k.actions == vec![Action::Search]
+            && SEARCH_INDEXES
+                .iter()
+                .all(|i| k.indexes.iter().any(|uid| uid == "*" || uid == i))
+    }))
+}
+
 async fn meilisearch_key(State(client): State<meilisearch_sdk::Client>) -> Result<Response> {
-    Ok(
-        if let Some(key) = client
-            .get_keys()
-            .await?
-            .results
-            .into_iter()
-            .find(|k| k.actions == vec![Action::Search])
-        {
-            ([("cache-control", "public, max-age=300")], key.key).into_response()
-        } else {
-            StatusCode::NOT_FOUND.into_response()
-        },
-    )
+    Ok(if let Some(key) = search_key(&client).await? {
+        ([("cache-control", "public, max-age=300")], key.key).into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    })
 }
 
 type Result<T, E = Error> = core::result::Result<T, E>;
@@ -0,0 +1,592 @@
+//! An on-disk archive of every response fetched from the suppliers.
+//!
+//! Bodies are gzipped and stored by their SHA-256 (so an unchanged page
+//! costs nothing extra), and every fetch is appended to an index that
+//! records the supplier, menu reference, URL and time. Archived responses
+//! can be exported as a cassette (see [`crate::client`]) to serve as a test
+//! fixture.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use stor::menu::Supplier;
+use time::{format_description::well_known::Rfc3339, Date, OffsetDateTime};
+use tracing::{debug, info};
+
+const INDEX: &str = "index.ndjson";
+/// Locked while the index is appended to or rewritten, by any process.
+const INDEX_LOCK: &str = "index.lock";
+const OBJECTS: &str = "objects";
+
+/// How long an unreferenced body is kept when pruning, as it may belong to an
+/// entry that another process is about to record.
+const OBJECT_GRACE: Duration = Duration::from_secs(60 * 60);
+
+/// A single archived fetch.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Entry {
+    #[serde(with = "time::serde::rfc3339")]
+    pub fetched_at: OffsetDateTime,
+    pub supplier: Option<Supplier>,
+    /// The menu being fetched, if any (listing menus has no reference).
+    pub reference: Option<String>,
+    /// The dates the menu was fetched for, as some suppliers derive their
+    /// URLs from them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dates: Option<RangeInclusive<Date>>,
+    pub method: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    pub status: u16,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// SHA-256 of the (uncompressed) response body.
+    pub hash: String,
+}
+
+/// How long archived responses are kept.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Remove entries older than this.
+    pub max_age: Option<Duration>,
+    /// Remove the oldest entries until the stored bodies take up at most
+    /// this many bytes (compressed).
+    pub max_size: Option<u64>,
+    /// Keep unreferenced bodies that were written more recently than this.
+    pub grace: Duration,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Pruned {
+    pub entries: usize,
+    pub objects: usize,
+}
+
+#[derive(Debug, clap::Args)]
+#[group(id = "archive")]
+pub struct Args {
+    /// Save every fetched response to this directory.
+    #[arg(long = "archive", env = "MUNIN_ARCHIVE")]
+    pub archive_dir: Option<PathBuf>,
+
+    /// Remove archived responses older than this many days. Applied by
+    /// `munin serve` and `munin archive prune`.
+    #[arg(long, default_value = "30")]
+    pub archive_max_age_days: u64,
+
+    /// Remove the oldest archived responses until the archive takes up at
+    /// most this many megabytes. Applied like `--archive-max-age-days`.
+    #[arg(long)]
+    pub archive_max_size_mb: Option<u64>,
+}
+
+impl Args {
+    #[must_use]
+    pub fn retention(&self) -> Retention {
+        Retention {
+            max_age: Some(Duration::from_secs(
+                self.archive_max_age_days * 24 * 60 * 60,
+            )),
+            max_size: self.archive_max_size_mb.map(|mb| mb * 1_000_000),
+            grace: OBJECT_GRACE,
+        }
+    }
+
+    /// Open the archive, if one was requested.
+    pub fn open(&self) -> io::Result<Option<Archive>> {
+        let Some(dir) = &self.archive_dir else {
+            return Ok(None);
+        };
+
+        let archive = Archive::open(dir)?;
+        info!(dir = %dir.display(), "opened archive");
+
+        Ok(Some(archive))
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Write archived responses as a cassette, for use as a test fixture.
+    Export {
+        #[arg(long = "archive", env = "MUNIN_ARCHIVE")]
+        archive_dir: PathBuf,
+
+        #[arg(long)]
+        supplier: Option<Supplier>,
+
+        #[arg(long)]
+        reference: Option<String>,
+
+        /// Only export responses fetched at or after this time (RFC 3339).
+        #[arg(long, value_parser = parse_rfc3339)]
+        since: Option<OffsetDateTime>,
+
+        /// The cassette directory, e.g. `fixtures/sabis`.
+        out: PathBuf,
+    },
+    /// Apply the retention settings without indexing.
+    Prune(Args),
+}
+
+fn parse_rfc3339(s: &str) -> Result<OffsetDateTime, time::error::Parse> {
+    OffsetDateTime::parse(s, &Rfc3339)
+}
+
+pub fn run(cmd: Command) -> anyhow::Result<()> {
+    match cmd {
+        Command::Export {
+            archive_dir,
+            supplier,
+            reference,
+            since,
+            out,
+        } => {
+            let archive = Archive::open(archive_dir)?;
+            let entries = archive
+                .entries()?
+                .into_iter()
+                .filter(|e| supplier.is_none() || e.supplier == supplier)
+                .filter(|e| reference.is_none() || e.reference == reference)
+                .filter(|e| !matches!(since, Some(t) if e.fetched_at < t))
+                .collect::<Vec<_>>();
+
+            crate::client::export(&archive, &entries, &out)?;
+            info!(entries = entries.len(), out = %out.display(), "exported cassette");
+        }
+        Command::Prune(args) => {
+            let Some(archive) = args.open()? else {
+                anyhow::bail!("no archive specified");
+            };
+
+            let pruned = archive.prune(args.retention(), OffsetDateTime::now_utc())?;
+            info!(pruned.entries, pruned.objects, "pruned archive");
+        }
+    }
+
+    Ok(())
+}
+
+/// The archive may be shared by several processes, e.g. multiple `munin
+/// serve` workers, so the index is guarded by a file lock rather than a
+/// mutex. The methods block, and should not be called on an async runtime.
+#[derive(Debug)]
+pub struct Archive {
+    dir: PathBuf,
+}
+
+impl Archive {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(dir.join(OBJECTS))?;
+
+        Ok(Self { dir })
+    }
+
+    /// Lock the index, exclusively unless `shared`, until the returned file
+    /// is dropped.
+    fn lock_index(&self, shared: bool) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(self.dir.join(INDEX_LOCK))?;
+
+        if shared {
+            FileExt::lock_shared(&file)?;
+        } else {
+            FileExt::lock_exclusive(&file)?;
+        }
+
+        Ok(file)
+    }
+
+    fn object_path(&self, hash: &str) -> PathBuf {
+        self.dir
+            .join(OBJECTS)
+            .join(&hash[..2])
+            .join(format!("{hash}.gz"))
+    }
+
+    /// The hash a body is stored by.
+    #[must_use]
+    pub fn hash(body: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(body))
+    }
+
+    /// Store a response body, returning its hash. Record the entry that
+    /// references it first, or a concurrent [`Archive::prune`] may remove
+    /// it again.
+    pub fn put(&self, body: &[u8]) -> io::Result<String> {
+        let hash = Self::hash(body);
+        let path = self.object_path(&hash);
+
+        if !path.exists() {
+            fs::create_dir_all(path.parent().expect("object has a parent"))?;
+
+            // write to a temporary file first, so that neither a crash nor a
+            // concurrent write of the same body can leave a truncated object
+            let tmp = path.with_extension(format!("{:x}.tmp", rand::random::<u32>()));
+            let mut encoder = GzEncoder::new(File::create(&tmp)?, Compression::default());
+            encoder.write_all(body)?;
+            encoder.finish()?.sync_all()?;
+            fs::rename(tmp, path)?;
+        }
+
+        Ok(hash)
+    }
+
+    /// Read the body with the given hash.
+    pub fn get(&self, hash: &str) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        GzDecoder::new(File::open(self.object_path(hash))?).read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    pub fn record(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let _lock = self.lock_index(false)?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(INDEX))?
+            .write_all(&line)
+    }
+
+    /// All entries, oldest first.
+    pub fn entries(&self) -> io::Result<Vec<Entry>> {
+        let _lock = self.lock_index(true)?;
+        self.read_index()
+    }
+
+    fn read_index(&self) -> io::Result<Vec<Entry>> {
+        let file = match File::open(self.dir.join(INDEX)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = BufReader::new(file)
+            .lines()
+            .filter(|l| l.as_ref().map_or(true, |l| !l.is_empty()))
+            .map(|l| Ok(serde_json::from_str::<Entry>(&l?)?))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.fetched_at);
+
+        Ok(entries)
+    }
+
+    /// Remove entries according to `retention`, and then any bodies that are
+    /// no longer referenced. Bodies that are still being written, or were
+    /// written within the grace period, are left alone.
+    ///
+    /// The index stays locked until the bodies are removed, as an entry that
+    /// is recorded meanwhile may reference a body that is about to be.
+    pub fn prune(&self, retention: Retention, now: OffsetDateTime) -> io::Result<Pruned> {
+        let _lock = self.lock_index(false)?;
+        let entries = self.read_index()?;
+        let total = entries.len();
+
+        let mut kept = Vec::new();
+        let mut size = 0;
+        let mut seen = HashSet::new();
+
+        for entry in entries.into_iter().rev() {
+            if matches!(retention.max_age, Some(max_age) if now - entry.fetched_at > max_age) {
+                continue;
+            }
+
+            if seen.insert(entry.hash.clone()) {
+                size += fs::metadata(self.object_path(&entry.hash)).map_or(0, |m| m.len());
+            }
+
+            if matches!(retention.max_size, Some(max_size) if size > max_size) {
+                break;
+            }
+
+            kept.push(entry);
+        }
+
+        kept.reverse();
+
+        let mut index = Vec::new();
+        for entry in &kept {
+            serde_json::to_writer(&mut index, entry)?;
+            index.push(b'\n');
+        }
+        let tmp = self
+            .dir
+            .join(INDEX)
+            .with_extension(format!("{:x}.tmp", rand::random::<u32>()));
+        fs::write(&tmp, index)?;
+        fs::rename(tmp, self.dir.join(INDEX))?;
+
+        let referenced = kept.iter().map(|e| e.hash.as_str()).collect::<HashSet<_>>();
+        let mut objects = 0;
+
+        for shard in fs::read_dir(self.dir.join(OBJECTS))? {
+            let shard = shard?.path();
+
+            for object in fs::read_dir(&shard)? {
+                let object = object?;
+                let path = object.path();
+                let hash = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+
+                if referenced.contains(hash) {
+                    continue;
+                }
+
+                // still being written by `put`
+                if matches!(path.extension(), Some(e) if e == "tmp") {
+                    continue;
+                }
+
+                // the clock of the file system, not `now`, which may be in
+                // the past
+                let fresh = match object.metadata()?.modified()?.elapsed() {
+                    Ok(age) => age < retention.grace,
+                    Err(_) => true,
+                };
+
+                if !fresh {
+                    fs::remove_file(&path)?;
+                    objects += 1;
+                }
+            }
+
+            // fails if the shard is not empty, which is fine
+            let _ = fs::remove_dir(&shard);
+        }
+
+        let pruned = Pruned {
+            entries: total - kept.len(),
+            objects,
+        };
+        debug!(?pruned, "pruned archive");
+
+        Ok(pruned)
+    }
+
+    #[must_use]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+/// The archived responses that were the latest at some point in time, for
+/// replaying a past fetch (see [`crate::client::Client::replay_snapshot`]).
+#[derive(Debug)]
+pub struct Snapshot {
+    archive: Arc<Archive>,
+    as_of: OffsetDateTime,
+    responses: HashMap<(String, String, Option<String>), Entry>,
+}
+
+impl Snapshot {
+    /// `entries` must be sorted oldest first, like [`Archive::entries`]
+    /// returns them.
+    #[must_use]
+    pub fn new(archive: Arc<Archive>, entries: &[Entry], as_of: OffsetDateTime) -> Self {
+        let responses = entries
+            .iter()
+            .take_while(|e| e.fetched_at <= as_of)
+            .map(|e| {
+                (
+                    (e.method.clone(), e.url.clone(), e.request_body.clone()),
+                    e.clone(),
+                )
+            })
+            .collect();
+
+        Self {
+            archive,
+            as_of,
+            responses,
+        }
+    }
+
+    /// The time the snapshot was taken, which a replaying client reports as
+    /// the current time (see [`crate::client::Client::now`]).
+    #[must_use]
+    pub fn as_of(&self) -> OffsetDateTime {
+        self.as_of
+    }
+
+    #[must_use]
+    pub fn get(&self, method: &str, url: &str, request_body: Option<&str>) -> Option<&Entry> {
+        self.responses.get(&(
+            method.to_owned(),
+            url.to_owned(),
+            request_body.map(ToOwned::to_owned),
+        ))
+    }
+
+    pub fn body(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+        self.archive.get(&entry.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tempdir::TempDir;
+    use time::macros::datetime;
+
+    use super::{Archive, Entry, Pruned, Retention, Snapshot};
+
+    fn entry(archive: &Archive, fetched_at: time::OffsetDateTime, body: &str) -> Entry {
+        let entry = Entry {
+            fetched_at,
+            supplier: Some(stor::menu::Supplier::Sabis),
+            reference: Some("carnegie".to_owned()),
+            dates: None,
+            method: "GET".to_owned(),
+            url: "https://www.sabis.se/".to_owned(),
+            request_body: None,
+            status: 200,
+            headers: Default::default(),
+            hash: archive.put(body.as_bytes()).unwrap(),
+        };
+        archive.record(&entry).unwrap();
+        entry
+    }
+
+    #[test]
+    fn put_get() {
+        let dir = TempDir::new("archive").unwrap();
+        let archive = Archive::open(dir.path()).unwrap();
+
+        let a = archive.put(b"hello").unwrap();
+        assert_eq!(a, archive.put(b"hello").unwrap());
+        assert_ne!(a, archive.put(b"world").unwrap());
+        assert_eq!(archive.get(&a).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn prune() {
+        let dir = TempDir::new("archive").unwrap();
+        let archive = Archive::open(dir.path()).unwrap();
+
+        let old = entry(&archive, datetime!(2023-01-01 12:00 UTC), "old");
+        let new = entry(&archive, datetime!(2023-02-01 12:00 UTC), "new");
+        let now = datetime!(2023-02-02 12:00 UTC);
+
+        assert_eq!(archive.entries().unwrap(), [old.clone(), new.clone()]);
+
+        let retention = Retention {
+            max_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+            ..Default::default()
+        };
+        assert_eq!(
+            archive.prune(retention, now).unwrap(),
+            Pruned {
+                entries: 1,
+                objects: 1
+            }
+        );
+        assert!(archive.get(&old.hash).is_err());
+        assert_eq!(archive.get(&new.hash).unwrap(), b"new");
+        assert_eq!(archive.entries().unwrap(), [new]);
+
+        let retention = Retention {
+            max_size: Some(0),
+            ..Default::default()
+        };
+        archive.prune(retention, now).unwrap();
+        assert!(archive.entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn prune_spares_unrecorded_bodies() {
+        let dir = TempDir::new("archive").unwrap();
+        let archive = Archive::open(dir.path()).unwrap();
+
+        let hash = archive.put(b"not recorded yet").unwrap();
+        let tmp = archive.object_path(&hash).with_extension("1234.tmp");
+        std::fs::write(&tmp, b"half written").unwrap();
+
+        let retention = Retention {
+            grace: Duration::from_secs(60),
+            ..Default::default()
+        };
+        assert_eq!(
+            archive
+                .prune(retention, time::OffsetDateTime::now_utc())
+                .unwrap(),
+            Pruned::default()
+        );
+        assert_eq!(archive.get(&hash).unwrap(), b"not recorded yet");
+        assert!(tmp.exists());
+
+        archive
+            .prune(Retention::default(), time::OffsetDateTime::now_utc())
+            .unwrap();
+        assert!(archive.get(&hash).is_err());
+        assert!(tmp.exists());
+    }
+
+    #[test]
+    fn concurrent_record_and_prune() {
+        let dir = TempDir::new("archive").unwrap();
+        // one archive per process, which only share the directory
+        let recorder = Archive::open(dir.path()).unwrap();
+        let pruner = Archive::open(dir.path()).unwrap();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..200 {
+                    entry(&recorder, time::OffsetDateTime::now_utc(), &i.to_string());
+                }
+            });
+
+            s.spawn(|| {
+                for _ in 0..50 {
+                    let retention = Retention {
+                        grace: Duration::from_secs(60),
+                        ..Default::default()
+                    };
+                    pruner
+                        .prune(retention, time::OffsetDateTime::now_utc())
+                        .unwrap();
+                }
+            });
+        });
+
+        assert_eq!(pruner.entries().unwrap().len(), 200);
+    }
+
+    #[test]
+    fn snapshot() {
+        let dir = TempDir::new("archive").unwrap();
+        let archive = Arc::new(Archive::open(dir.path()).unwrap());
+
+        entry(&archive, datetime!(2023-01-01 12:00 UTC), "january");
+        entry(&archive, datetime!(2023-02-01 12:00 UTC), "february");
+        let entries = archive.entries().unwrap();
+
+        let body = |as_of| {
+            let snapshot = Snapshot::new(archive.clone(), &entries, as_of);
+            let entry = snapshot.get("GET", "https://www.sabis.se/", None)?;
+            Some(snapshot.body(entry).unwrap())
+        };
+
+        assert_eq!(body(datetime!(2022-12-31 12:00 UTC)), None);
+        assert_eq!(body(datetime!(2023-01-15 12:00 UTC)).unwrap(), b"january");
+        assert_eq!(body(datetime!(2023-03-01 12:00 UTC)).unwrap(), b"february");
+    }
+}
@@ -0,0 +1,313 @@
+//! Scrape a canary menu of each supplier and check that its pages still look
+//! the way the scraper expects. A scraper whose markup changed usually finds
+//! nothing rather than failing, which looks just like an empty menu.
+
+use std::{fmt, ops::RangeInclusive, sync::Arc};
+
+use anyhow::bail;
+use futures::{stream, StreamExt};
+use select::{document::Document, predicate::Class};
+use stor::{config::Config, menu::Supplier, Day};
+use time::{Date, Duration, OffsetDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::error;
+
+use crate::{
+    client::{Capture, Client, Page},
+    supplier::{self, Canary, ListDays},
+};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Only check this supplier.
+    #[arg(long)]
+    supplier: Option<Supplier>,
+
+    /// Check this menu instead of the canary of the supplier.
+    #[arg(long, requires = "supplier")]
+    reference: Option<String>,
+
+    /// How many days after today to fetch.
+    #[arg(long, default_value = "14")]
+    days: u32,
+}
+
+/// The step of a check that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The days could not be fetched at all.
+    Fetch,
+    /// Elements the scraper looks for are missing.
+    Selectors,
+    /// No days were found.
+    Days,
+    /// Dates outside of the requested range, or the same date twice.
+    Dates,
+    /// No meals were found, or some meals are empty.
+    Meals,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Step::Fetch => "fetch",
+            Step::Selectors => "selectors",
+            Step::Days => "days",
+            Step::Dates => "dates",
+            Step::Meals => "meals",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failed {
+    pub step: Step,
+    pub reason: String,
+}
+
+impl Failed {
+    fn new(step: Step, reason: impl Into<String>) -> Self {
+        Self {
+            step,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// What a successful check found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Found {
+    pub days: usize,
+    pub meals: usize,
+}
+
+/// Check what was scraped from `pages` against the expectations of
+/// `canary`.
+fn validate(
+    canary: &Canary,
+    date_range: bool,
+    pages: &[Page],
+    days: &[Day],
+    dates: &RangeInclusive<Date>,
+) -> Result<Found, Failed> {
+    let docs = pages
+        .iter()
+        .map(|p| Document::from(p.body.as_str()))
+        .collect::<Vec<_>>();
+    let missing = canary
+        .classes
+        .iter()
+        .filter(|class| !docs.iter().any(|d| d.find(Class(**class)).next().is_some()))
+        .map(|class| format!(".{class}"))
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        return Err(Failed::new(
+            Step::Selectors,
+            format!("not found: {}", missing.join(", ")),
+        ));
+    }
+
+    if days.is_empty() {
+        return Err(Failed::new(
+            Step::Days,
+            "no days found (the dates might not parse)",
+        ));
+    }
+
+    let mut seen = days.iter().map(|d| d.date).collect::<Vec<_>>();
+    seen.sort();
+
+    if let Some(date) = seen.iter().find(|d| date_range && !dates.contains(d)) {
+        return Err(Failed::new(
+            Step::Dates,
+            format!("{date} is outside of {} to {}", dates.start(), dates.end()),
+        ));
+    }
+
+    if let Some(w) = seen.windows(2).find(|w| w[0] == w[1]) {
+        return Err(Failed::new(Step::Dates, format!("{} appears twice", w[0])));
+    }
+
+    let meals = days.iter().map(|d| d.meals.len()).sum();
+
+    if meals == 0 {
+        return Err(Failed::new(Step::Meals, "no meals found"));
+    }
+
+    if let Some(day) = days
+        .iter()
+        .find(|d| d.meals.iter().any(|m| m.trim().is_empty()))
+    {
+        return Err(Failed::new(
+            Step::Meals,
+            format!("empty meal on {}", day.date),
+        ));
+    }
+
+    Ok(Found {
+        days: days.len(),
+        meals,
+    })
+}
+
+/// Scrape `reference` and validate the result against the canary of
+/// `supplier`.
+async fn check_menu(
+    client: &Client,
+    supplier: &dyn supplier::Supplier,
+    reference: &str,
+    dates: RangeInclusive<Date>,
+) -> Result<Found, Failed> {
+    let capture = Arc::new(Capture::default());
+    let client = client.clone().with_capture(capture.clone());
+
+    let res = crate::list_days(&client, supplier.id(), reference, dates.clone()).await;
+    let ListDays { days, .. } = res.map_err(|e| Failed::new(Step::Fetch, format!("{e:#}")))?;
+
+    validate(
+        &supplier.canary(),
+        supplier.capabilities().date_range,
+        &capture.take(),
+        &days,
+        &dates,
+    )
+}
+
+pub async fn check(opt: Args, config: &Config) -> anyhow::Result<()> {
+    let client = crate::http_client(config)?;
+    let start = OffsetDateTime::now_utc().to_timezone(crate::TZ).date();
+    let dates = start..=start + Duration::days(opt.days.into());
+
+    let suppliers = supplier::SUPPLIERS
+        .iter()
+        .copied()
+        .filter(|s| match opt.supplier {
+            Some(id) => id == s.id(),
+            // disabled suppliers are only checked when asked for
+            None => config.supplier(s.id()).enabled,
+        });
+
+    let results = stream::iter(suppliers)
+        .map(|s| {
+            let reference = opt
+                .reference
+                .clone()
+                .unwrap_or_else(|| s.canary().reference.to_owned());
+            let (client, dates) = (&client, dates.clone());
+
+            async move {
+                let res = check_menu(client, s, &reference, dates).await;
+                (s.id(), reference, res)
+            }
+        })
+        .buffered(supplier::SUPPLIERS.len())
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut failed = 0;
+
+    for (supplier, reference, res) in results {
+        let name = supplier.to_string();
+
+        match res {
+            Ok(Found { days, meals }) => {
+                println!("ok      {name:<10} {reference}: {days} days, {meals} meals");
+            }
+            Err(Failed { step, reason }) => {
+                failed += 1;
+                error!(%supplier, %reference, %step, "check failed: {reason}");
+                println!("FAILED  {name:<10} {reference}: {step}: {reason}");
+            }
+        }
+    }
+
+    if failed > 0 {
+        bail!("{failed} supplier(s) failed the check");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use time::macros::date;
+
+    use crate::{client::fixture, supplier::sabis::Sabis};
+
+    use super::*;
+
+    const CANARY: Canary = Canary {
+        reference: "1",
+        classes: &["day", "meal"],
+    };
+
+    fn page(body: &str) -> Page {
+        Page {
+            url: "https://example.com/".parse().unwrap(),
+            status: StatusCode::OK,
+            body: body.to_owned(),
+        }
+    }
+
+    fn day(date: Date, meals: &[&str]) -> Day {
+        Day::new(date, meals.iter().map(ToString::to_string).collect())
+    }
+
+    #[test]
+    fn validate_steps() {
+        let dates = date!(2023 - 02 - 06)..=date!(2023 - 02 - 12);
+        let pages = [page(r#"<div class="day"><p class="meal">Fisk</p></div>"#)];
+        let step = |pages: &[Page], days: &[Day]| {
+            validate(&CANARY, true, pages, days, &dates).map_err(|f| f.step)
+        };
+
+        assert_eq!(
+            step(&pages, &[day(date!(2023 - 02 - 06), &["Fisk"])]),
+            Ok(Found { days: 1, meals: 1 })
+        );
+        assert_eq!(
+            step(&[page(r#"<div class="day"></div>"#)], &[]),
+            Err(Step::Selectors)
+        );
+        assert_eq!(step(&pages, &[]), Err(Step::Days));
+        assert_eq!(
+            step(&pages, &[day(date!(2023 - 03 - 06), &["Fisk"])]),
+            Err(Step::Dates)
+        );
+        assert_eq!(
+            step(
+                &pages,
+                &[
+                    day(date!(2023 - 02 - 06), &["Fisk"]),
+                    day(date!(2023 - 02 - 06), &["Soppa"])
+                ]
+            ),
+            Err(Step::Dates)
+        );
+        assert_eq!(
+            step(&pages, &[day(date!(2023 - 02 - 06), &[])]),
+            Err(Step::Meals)
+        );
+        assert_eq!(
+            step(&pages, &[day(date!(2023 - 02 - 06), &["Fisk", " "])]),
+            Err(Step::Meals)
+        );
+    }
+
+    #[tokio::test]
+    async fn sabis_canary() {
+        // sabis only publishes the current week, so the range is ignored
+        let found = check_menu(
+            &fixture("sabis"),
+            &Sabis,
+            "carnegie",
+            date!(2020 - 01 - 01)..=date!(2020 - 01 - 14),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(found.days, 5);
+    }
+}
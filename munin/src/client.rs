@@ -0,0 +1,1067 @@
+//! The HTTP client used by the suppliers.
+//!
+//! Besides making ordinary requests, the client can record every exchange to
+//! a directory (a *cassette*) and later replay it without touching the
+//! network. This is what the supplier tests run against.
+//!
+//! Responses can also be saved to an [`Archive`], regardless of whether they
+//! are recorded to a cassette, or kept in memory with a [`Capture`].
+//!
+//! Requests are also throttled per host (see [`RateLimit`]), so that an index
+//! run with many menus in flight does not get us blocked by a supplier.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use reqwest::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+        RETRY_AFTER,
+    },
+    IntoUrl, Method, Request, Response, ResponseBuilderExt, StatusCode, Url,
+};
+use serde::{Deserialize, Serialize};
+use stor::menu::Supplier;
+use thiserror::Error;
+use time::{Date, OffsetDateTime};
+use tokio::{
+    sync::{Semaphore, SemaphorePermit},
+    time::Instant,
+};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    archive::{self, Archive, Snapshot},
+    error::ScrapeError,
+    metrics,
+};
+
+const CASSETTE_INDEX: &str = "index.json";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+
+    #[error("no recorded response for {method} {url}")]
+    NotRecorded { method: Method, url: String },
+
+    #[error("cassette error: {0}")]
+    Cassette(#[from] io::Error),
+}
+
+/// A recorded request and its response. The response body is stored in a
+/// separate file next to the index, so that it can be inspected and edited
+/// by hand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    method: String,
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_body: Option<String>,
+    status: u16,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+impl Interaction {
+    fn matches(&self, method: &str, url: &str, request_body: Option<&str>) -> bool {
+        self.method == method && self.url == url && self.request_body.as_deref() == request_body
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug)]
+struct Cassette {
+    dir: PathBuf,
+    mode: Mode,
+    interactions: Mutex<Vec<Interaction>>,
+}
+
+impl Cassette {
+    fn load(dir: PathBuf, mode: Mode) -> io::Result<Self> {
+        let interactions = match fs::read(dir.join(CASSETTE_INDEX)) {
+            Ok(json) => serde_json::from_slice(&json)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && mode == Mode::Record => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            dir,
+            mode,
+            interactions: Mutex::new(interactions),
+        })
+    }
+
+    fn replay(
+        &self,
+        method: &Method,
+        url: &reqwest::Url,
+        request_body: Option<&str>,
+    ) -> Result<Response, Error> {
+        let interaction = self
+            .interactions
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|i| i.matches(method.as_str(), url.as_str(), request_body))
+            .cloned()
+            .ok_or_else(|| Error::NotRecorded {
+                method: method.clone(),
+                url: url.to_string(),
+            })?;
+
+        let body = fs::read(self.dir.join(&interaction.body))?;
+
+        build_response(
+            url.clone(),
+            interaction.status,
+            interaction
+                .headers
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str())),
+            body,
+        )
+    }
+
+    fn record(&self, interaction: Interaction, body: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(&interaction.body), body)?;
+
+        let mut interactions = self.interactions.lock().unwrap();
+        interactions.retain(|i| {
+            !i.matches(
+                &interaction.method,
+                &interaction.url,
+                interaction.request_body.as_deref(),
+            )
+        });
+        interactions.push(interaction);
+        interactions.sort_by(|a, b| (&a.url, &a.method).cmp(&(&b.url, &b.method)));
+
+        let json = serde_json::to_vec_pretty(&*interactions)?;
+        fs::write(self.dir.join(CASSETTE_INDEX), json)
+    }
+}
+
+fn build_response<'a>(
+    url: reqwest::Url,
+    status: u16,
+    headers: impl Iterator<Item = (&'a str, &'a str)>,
+    body: Vec<u8>,
+) -> Result<Response, Error> {
+    let mut builder = http::Response::builder().status(status).url(url);
+
+    for (name, value) in headers {
+        let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value))
+        else {
+            continue;
+        };
+        builder = builder.header(name, value);
+    }
+
+    let res = builder
+        .body(body)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    Ok(Response::from(res))
+}
+
+fn request_body(req: &Request) -> Option<String> {
+    req.body()
+        .and_then(|b| b.as_bytes())
+        .filter(|b| !b.is_empty())
+        .map(|b| String::from_utf8_lossy(b).into_owned())
+}
+
+/// Name the body file after the request, using a hash that is stable across
+/// builds (unlike [`std::collections::hash_map::DefaultHasher`]).
+fn body_file_name(
+    method: &Method,
+    url: &str,
+    request_body: Option<&str>,
+    content_type: Option<&str>,
+) -> String {
+    let mut request = format!("{method} {url}");
+
+    // the body is only hashed if there is one, so that the names recorded
+    // for requests without a body do not change when one is added to
+    // another request
+    if let Some(body) = request_body {
+        request.push('\n');
+        request.push_str(body);
+    }
+
+    let hash = request.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+
+    let ext = match content_type {
+        Some(t) if t.contains("json") => "json",
+        Some(t) if t.contains("html") => "html",
+        _ => "txt",
+    };
+
+    format!("{hash:016x}.{ext}")
+}
+
+/// How hard we may hit a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Maximum number of requests in flight at once.
+    pub concurrency: usize,
+    /// Minimum time between starting two requests.
+    pub interval: Duration,
+}
+
+impl RateLimit {
+    #[must_use]
+    pub const fn new(concurrency: usize, interval: Duration) -> Self {
+        Self {
+            concurrency,
+            interval,
+        }
+    }
+
+    /// The limit that satisfies both `self` and `other`.
+    #[must_use]
+    pub fn min(self, other: Self) -> Self {
+        Self {
+            concurrency: self.concurrency.min(other.concurrency),
+            interval: self.interval.max(other.interval),
+        }
+    }
+}
+
+impl Default for RateLimit {
+    /// Used for hosts that no supplier has declared.
+    fn default() -> Self {
+        Self::new(4, Duration::from_millis(250))
+    }
+}
+
+#[derive(Debug)]
+struct Host {
+    limit: RateLimit,
+    in_flight: Semaphore,
+    next_request: tokio::sync::Mutex<Instant>,
+}
+
+impl Host {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            in_flight: Semaphore::new(limit.concurrency.max(1)),
+            next_request: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until we are allowed to make a request. The request counts as in
+    /// flight until the permit is dropped.
+    async fn acquire(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .in_flight
+            .acquire()
+            .await
+            .expect("semaphore is never closed");
+
+        let mut next_request = self.next_request.lock().await;
+        tokio::time::sleep_until(*next_request).await;
+        *next_request = Instant::now() + self.limit.interval;
+
+        permit
+    }
+}
+
+#[derive(Debug, Default)]
+struct Hosts(Mutex<HashMap<String, Arc<Host>>>);
+
+impl Hosts {
+    fn get(&self, host: &str) -> Arc<Host> {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(host.to_owned())
+            .or_insert_with(|| Arc::new(Host::new(RateLimit::default())))
+            .clone()
+    }
+
+    fn limit(&self, host: &str, limit: RateLimit) {
+        let mut hosts = self.0.lock().unwrap();
+        let limit = hosts.get(host).map_or(limit, |h| h.limit.min(limit));
+        hosts.insert(host.to_owned(), Arc::new(Host::new(limit)));
+    }
+}
+
+/// How requests that failed with a (probably) transient error are retried.
+/// Only idempotent requests are retried, and the delay between attempts is
+/// independent of [`RateLimit::interval`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    /// Maximum number of attempts, including the first one.
+    pub attempts: u32,
+    /// Upper bound of the (jittered) delay before the first retry. It is
+    /// doubled for every subsequent retry.
+    pub base_delay: Duration,
+    /// Never wait longer than this, not even if the server asks us to using
+    /// `Retry-After`.
+    pub max_delay: Duration,
+}
+
+impl Retry {
+    /// Give up after the first attempt.
+    pub const NEVER: Self = Self {
+        attempts: 1,
+        base_delay: Duration::ZERO,
+        max_delay: Duration::ZERO,
+    };
+
+    /// "Full jitter" exponential backoff: a random delay between zero and
+    /// `base_delay * 2^(attempt - 1)`.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceil = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+
+        ceil.mul_f64(rand::random())
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Whether a response with this status is worth trying again later.
+pub(crate) fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Parse a `Retry-After` header, which is either a number of seconds or an
+/// HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// What the requests of a client are made for, as recorded in the
+/// [`Archive`].
+#[derive(Debug, Clone, Default)]
+struct Scope {
+    supplier: Option<Supplier>,
+    reference: Option<String>,
+    dates: Option<RangeInclusive<Date>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Client {
+    inner: reqwest::Client,
+    cassette: Option<Arc<Cassette>>,
+    hosts: Arc<Hosts>,
+    retry: Retry,
+    archive: Option<Arc<Archive>>,
+    snapshot: Option<Arc<Snapshot>>,
+    capture: Option<Arc<Capture>>,
+    /// Hosts whose requests are sent elsewhere, see [`Client::with_host`].
+    rewrites: Arc<HashMap<String, Url>>,
+    scope: Scope,
+}
+
+impl From<reqwest::Client> for Client {
+    fn from(inner: reqwest::Client) -> Self {
+        Self {
+            inner,
+            cassette: None,
+            hosts: Arc::default(),
+            retry: Retry::default(),
+            archive: None,
+            snapshot: None,
+            capture: None,
+            rewrites: Arc::default(),
+            scope: Scope::default(),
+        }
+    }
+}
+
+impl Client {
+    /// Perform requests as usual, but save every response to `dir`.
+    pub fn record(inner: reqwest::Client, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            cassette: Some(Arc::new(Cassette::load(dir.into(), Mode::Record)?)),
+            ..Self::from(inner)
+        })
+    }
+
+    /// Answer requests with the responses previously recorded to `dir`.
+    /// Requests that were never recorded fail with [`Error::NotRecorded`].
+    pub fn replay(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        Ok(Self {
+            cassette: Some(Arc::new(Cassette::load(dir.into(), Mode::Replay)?)),
+            ..Self::default()
+        })
+    }
+
+    #[must_use]
+    pub fn with_retry(self, retry: Retry) -> Self {
+        Self { retry, ..self }
+    }
+
+    /// Save every response fetched by this client to `archive`.
+    #[must_use]
+    pub fn with_archive(self, archive: Arc<Archive>) -> Self {
+        Self {
+            archive: Some(archive),
+            ..self
+        }
+    }
+
+    /// Keep every response received by this client in `capture`, including
+    /// replayed ones.
+    #[must_use]
+    pub fn with_capture(self, capture: Arc<Capture>) -> Self {
+        Self {
+            capture: Some(capture),
+            ..self
+        }
+    }
+
+    /// A client whose archived responses are attributed to `supplier` and
+    /// (optionally) one of its menus.
+    #[must_use]
+    pub fn scoped(&self, supplier: Supplier, reference: Option<&str>) -> Self {
+        Self {
+            scope: Scope {
+                supplier: Some(supplier),
+                reference: reference.map(ToOwned::to_owned),
+                dates: None,
+            },
+            ..self.clone()
+        }
+    }
+
+    /// A client whose archived responses were fetched for `dates`, so that
+    /// [`crate::reparse`] can ask for the same dates again.
+    #[must_use]
+    pub fn for_dates(self, dates: RangeInclusive<Date>) -> Self {
+        Self {
+            scope: Scope {
+                dates: Some(dates),
+                ..self.scope
+            },
+            ..self
+        }
+    }
+
+    /// Answer requests with archived responses, like [`Client::replay`].
+    /// The client pretends that it is the time of the snapshot.
+    #[must_use]
+    pub fn replay_snapshot(snapshot: Arc<Snapshot>) -> Self {
+        Self {
+            snapshot: Some(snapshot),
+            ..Self::default()
+        }
+    }
+
+    /// Whether the client answers requests from an archive snapshot.
+    #[must_use]
+    pub fn is_snapshot(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    /// The current time, or the time of the snapshot being replayed.
+    /// Suppliers whose URLs depend on the date must use this instead of
+    /// [`OffsetDateTime::now_utc`], or archived responses cannot be replayed.
+    #[must_use]
+    pub fn now(&self) -> OffsetDateTime {
+        self.snapshot
+            .as_deref()
+            .map_or_else(OffsetDateTime::now_utc, Snapshot::as_of)
+    }
+
+    /// Throttle requests to the host of `url`. If the host already has a
+    /// limit, the stricter of the two applies.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `url` is not a valid URL with a host.
+    #[must_use]
+    pub fn with_rate_limit(self, url: &str, limit: RateLimit) -> Self {
+        let url = reqwest::Url::parse(url).expect("invalid url");
+        let host = url.host_str().expect("url has no host");
+        self.hosts.limit(host, limit);
+        self
+    }
+
+    /// Send the requests to the host of `from` to the host of `to` instead,
+    /// e.g. a mirror or a proxy. Only the scheme, host and port of `to` are
+    /// used. The rate limit of the host of `from` does not carry over.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `from` or `to` is not a valid URL with a host.
+    #[must_use]
+    pub fn with_host(self, from: &str, to: &str) -> Self {
+        let from = reqwest::Url::parse(from).expect("invalid url");
+        let to = reqwest::Url::parse(to).expect("invalid url");
+        assert!(to.has_host(), "url has no host");
+
+        let mut rewrites = (*self.rewrites).clone();
+        rewrites.insert(from.host_str().expect("url has no host").to_owned(), to);
+
+        Self {
+            rewrites: Arc::new(rewrites),
+            ..self
+        }
+    }
+
+    pub fn get(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub fn post(&self, url: impl IntoUrl) -> RequestBuilder {
+        self.request(Method::POST, url)
+    }
+
+    pub fn request(&self, method: Method, url: impl IntoUrl) -> RequestBuilder {
+        RequestBuilder {
+            client: self.clone(),
+            inner: self.inner.request(method, url),
+        }
+    }
+
+    pub async fn execute(&self, mut req: Request) -> Result<Response, Error> {
+        self.rewrite(req.url_mut());
+
+        let host = req.url().host_str().unwrap_or_default().to_owned();
+        let res = self.fetch(req).await;
+
+        let supplier = self.scope.supplier.map(|s| s.to_string());
+        let status = res
+            .as_ref()
+            .map_or_else(|_| "error".to_owned(), |r| r.status().as_u16().to_string());
+        metrics::HTTP_REQUESTS
+            .with_label_values(&[supplier.as_deref().unwrap_or_default(), &host, &status])
+            .inc();
+
+        let res = res?;
+
+        match self.capture.as_deref() {
+            Some(capture) => capture.keep(res).await,
+            None => Ok(res),
+        }
+    }
+
+    fn rewrite(&self, url: &mut Url) {
+        let Some(to) = url.host_str().and_then(|h| self.rewrites.get(h)).cloned() else {
+            return;
+        };
+
+        // both are http(s), so none of these can fail
+        let _ = url.set_scheme(to.scheme());
+        let _ = url.set_host(to.host_str());
+        let _ = url.set_port(to.port());
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response, Error> {
+        if let Some(cassette) = self.cassette.as_deref().filter(|c| c.mode == Mode::Replay) {
+            trace!("replaying {} {}", req.method(), req.url());
+            return cassette.replay(req.method(), req.url(), request_body(&req).as_deref());
+        }
+
+        if let Some(snapshot) = self.snapshot.as_deref() {
+            trace!("replaying {} {} from archive", req.method(), req.url());
+
+            let entry = snapshot
+                .get(
+                    req.method().as_str(),
+                    req.url().as_str(),
+                    request_body(&req).as_deref(),
+                )
+                .ok_or_else(|| Error::NotRecorded {
+                    method: req.method().clone(),
+                    url: req.url().to_string(),
+                })?;
+
+            return build_response(
+                req.url().clone(),
+                entry.status,
+                entry.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+                snapshot.body(entry)?,
+            );
+        }
+
+        let idempotent = matches!(*req.method(), Method::GET | Method::HEAD);
+
+        for attempt in 1.. {
+            let next = if idempotent && attempt < self.retry.attempts {
+                req.try_clone()
+            } else {
+                None
+            };
+
+            let res = self.execute_once(req).await;
+
+            let Some(next) = next else {
+                return res;
+            };
+
+            let retry_after = match &res {
+                Ok(res) if is_transient(res.status()) => retry_after(res.headers()),
+                Err(Error::Reqwest(e)) if e.is_timeout() || e.is_connect() || e.is_request() => {
+                    None
+                }
+                _ => return res,
+            };
+
+            let delay = retry_after
+                .unwrap_or_else(|| self.retry.backoff(attempt))
+                .min(self.retry.max_delay);
+
+            match &res {
+                Ok(res) => {
+                    debug!(status = %res.status(), ?delay, attempt, "retrying {}", res.url())
+                }
+                Err(e) => debug!(?delay, attempt, "retrying after error: {e}"),
+            }
+
+            tokio::time::sleep(delay).await;
+            req = next;
+        }
+
+        unreachable!()
+    }
+
+    async fn execute_once(&self, req: Request) -> Result<Response, Error> {
+        let host = self.hosts.get(req.url().host_str().unwrap_or_default());
+        let permit = host.acquire().await;
+
+        if self.cassette.is_none() && self.archive.is_none() {
+            return Ok(self.inner.execute(req).await?);
+        }
+
+        let method = req.method().clone();
+        let url = req.url().clone();
+        let request_body = request_body(&req);
+
+        let res = self.inner.execute(req).await?;
+        let status = res.status();
+        let headers = res
+            .headers()
+            .iter()
+            .filter(|(name, _)| ![CONTENT_ENCODING, CONTENT_LENGTH].contains(name))
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect::<BTreeMap<_, _>>();
+        let body = res.bytes().await?;
+        drop(permit);
+
+        if let Some(archive) = self.archive.clone() {
+            let mut entry = archive::Entry {
+                fetched_at: OffsetDateTime::now_utc(),
+                supplier: self.scope.supplier,
+                reference: self.scope.reference.clone(),
+                dates: self.scope.dates.clone(),
+                method: method.to_string(),
+                url: url.to_string(),
+                request_body: request_body.clone(),
+                status: status.as_u16(),
+                headers: headers.clone(),
+                hash: String::new(),
+            };
+            let body = body.clone();
+
+            // compressing and writing the body blocks
+            let archived = tokio::task::spawn_blocking(move || {
+                entry.hash = Archive::hash(&body);
+
+                // recorded first, so that pruning does not take the body for
+                // an unreferenced one
+                archive.record(&entry)?;
+                archive.put(&body)
+            })
+            .await
+            .map_err(io::Error::from)
+            .and_then(|res| res);
+
+            // losing the archive is not worth failing the request over
+            if let Err(e) = archived {
+                warn!("failed to archive {method} {url}: {e}");
+            }
+        }
+
+        if let Some(cassette) = self.cassette.as_deref() {
+            trace!("recording {method} {url}");
+
+            cassette.record(
+                Interaction {
+                    method: method.to_string(),
+                    url: url.to_string(),
+                    request_body: request_body.clone(),
+                    status: status.as_u16(),
+                    body: body_file_name(
+                        &method,
+                        url.as_str(),
+                        request_body.as_deref(),
+                        headers.get(CONTENT_TYPE.as_str()).map(String::as_str),
+                    ),
+                    headers: headers.clone(),
+                },
+                &body,
+            )?;
+        }
+
+        build_response(
+            url,
+            status.as_u16(),
+            headers.iter().map(|(k, v)| (k.as_str(), v.as_str())),
+            body.to_vec(),
+        )
+    }
+}
+
+/// A thin wrapper around [`reqwest::RequestBuilder`] that sends the request
+/// through a [`Client`].
+#[derive(Debug)]
+#[must_use]
+pub struct RequestBuilder {
+    client: Client,
+    inner: reqwest::RequestBuilder,
+}
+
+impl RequestBuilder {
+    pub fn header<K, V>(self, key: K, value: V) -> Self
+    where
+        HeaderName: TryFrom<K>,
+        <HeaderName as TryFrom<K>>::Error: Into<http::Error>,
+        HeaderValue: TryFrom<V>,
+        <HeaderValue as TryFrom<V>>::Error: Into<http::Error>,
+    {
+        Self {
+            inner: self.inner.header(key, value),
+            ..self
+        }
+    }
+
+    pub fn query<T: Serialize + ?Sized>(self, query: &T) -> Self {
+        Self {
+            inner: self.inner.query(query),
+            ..self
+        }
+    }
+
+    pub async fn send(self) -> Result<Response, Error> {
+        let req = self.inner.build()?;
+        self.client.execute(req).await
+    }
+
+    /// Send the request and read the whole response.
+    pub async fn page(self) -> Result<Page, Error> {
+        Ok(Page::read(self.send().await?).await?)
+    }
+}
+
+/// A response body together with where it came from, so that scrape errors
+/// can refer back to it.
+#[derive(Debug)]
+pub struct Page {
+    pub url: Url,
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl Page {
+    pub async fn read(res: Response) -> reqwest::Result<Self> {
+        Ok(Self {
+            url: res.url().clone(),
+            status: res.status(),
+            body: res.text().await?,
+        })
+    }
+
+    /// Blame the page for `step` failing.
+    pub fn error(&self, step: impl Into<Cow<'static, str>>) -> ScrapeError {
+        ScrapeError::new(step).with_page(self)
+    }
+}
+
+/// The responses received by a client, see [`Client::with_capture`].
+#[derive(Debug, Default)]
+pub struct Capture {
+    pages: Mutex<Vec<Page>>,
+}
+
+impl Capture {
+    async fn keep(&self, res: Response) -> Result<Response, Error> {
+        let url = res.url().clone();
+        let status = res.status();
+        let headers = res.headers().clone();
+        let body = res.bytes().await?.to_vec();
+
+        self.pages.lock().unwrap().push(Page {
+            url: url.clone(),
+            status,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        });
+
+        build_response(
+            url,
+            status.as_u16(),
+            headers
+                .iter()
+                .filter(|(name, _)| ![CONTENT_ENCODING, CONTENT_LENGTH].contains(name))
+                .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?))),
+            body,
+        )
+    }
+
+    /// The responses received so far, oldest first.
+    pub fn take(&self) -> Vec<Page> {
+        std::mem::take(&mut *self.pages.lock().unwrap())
+    }
+}
+
+/// Write archived responses to a cassette in `dir`, e.g. to turn a broken
+/// page into a test fixture.
+pub fn export(archive: &Archive, entries: &[archive::Entry], dir: &Path) -> io::Result<()> {
+    let cassette = Cassette::load(dir.to_owned(), Mode::Record)?;
+
+    for entry in entries {
+        let method = Method::from_bytes(entry.method.as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let body = archive.get(&entry.hash)?;
+
+        cassette.record(
+            Interaction {
+                method: entry.method.clone(),
+                url: entry.url.clone(),
+                request_body: entry.request_body.clone(),
+                status: entry.status,
+                body: body_file_name(
+                    &method,
+                    &entry.url,
+                    entry.request_body.as_deref(),
+                    entry.headers.get(CONTENT_TYPE.as_str()).map(String::as_str),
+                ),
+                headers: entry.headers.clone(),
+            },
+            &body,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Client for a supplier test, backed by the cassette in
+/// `fixtures/<name>`. Set `MUNIN_RECORD=1` to re-record the cassette from
+/// the live site instead.
+#[cfg(test)]
+pub fn fixture(name: &str) -> Client {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fixtures")
+        .join(name);
+
+    if std::env::var_os("MUNIN_RECORD").is_some() {
+        Client::record(reqwest::Client::new(), dir).unwrap()
+    } else {
+        Client::replay(dir).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::{
+        header::{HeaderMap, HeaderValue, RETRY_AFTER},
+        Method, StatusCode,
+    };
+    use tempdir::TempDir;
+    use time::OffsetDateTime;
+    use tokio::time::Instant;
+
+    use crate::archive::{Archive, Entry};
+
+    use super::{body_file_name, Client, Error, Host, Hosts, RateLimit, Retry};
+
+    #[tokio::test]
+    async fn replay() {
+        let client = super::fixture("sabis");
+        let res = client
+            .get("https://www.sabis.se/restauranger-cafeer/vara-foretagsrestauranger/carnegie/")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.text().await.unwrap().contains("menu-block__dishes"));
+
+        assert!(matches!(
+            client.get("https://www.sabis.se/").send().await,
+            Err(Error::NotRecorded { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn with_host() {
+        let client =
+            super::fixture("sabis").with_host("https://sabis.example", "https://www.sabis.se");
+        let res = client
+            .get("https://sabis.example/restauranger-cafeer/vara-foretagsrestauranger/carnegie/")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        let host = Host::new(RateLimit::new(2, Duration::from_millis(50)));
+        let start = Instant::now();
+
+        let a = host.acquire().await;
+        let _b = host.acquire().await;
+        assert_eq!(host.in_flight.available_permits(), 0);
+        drop(a);
+        let _c = host.acquire().await;
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn stricter_limit_wins() {
+        let hosts = Hosts::default();
+        hosts.limit("example.com", RateLimit::new(8, Duration::from_millis(10)));
+        hosts.limit("example.com", RateLimit::new(2, Duration::ZERO));
+
+        assert_eq!(
+            hosts.get("example.com").limit,
+            RateLimit::new(2, Duration::from_millis(10))
+        );
+        assert_eq!(hosts.get("example.org").limit, RateLimit::default());
+    }
+
+    #[test]
+    fn backoff() {
+        let retry = Retry {
+            attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        };
+
+        for _ in 0..100 {
+            assert!(retry.backoff(1) <= Duration::from_millis(100));
+            assert!(retry.backoff(3) <= Duration::from_millis(400));
+            assert!(retry.backoff(30) <= Duration::from_secs(1));
+        }
+
+        assert_eq!(Retry::NEVER.backoff(1), Duration::ZERO);
+    }
+
+    #[test]
+    fn retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(super::retry_after(&headers), None);
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(super::retry_after(&headers), Some(Duration::from_secs(120)));
+
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(super::retry_after(&headers), Some(Duration::ZERO));
+
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("soon"));
+        assert_eq!(super::retry_after(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn export() {
+        let archive_dir = TempDir::new("archive").unwrap();
+        let archive = Archive::open(archive_dir.path()).unwrap();
+        let entry = Entry {
+            fetched_at: OffsetDateTime::now_utc(),
+            supplier: None,
+            reference: None,
+            dates: None,
+            method: "GET".to_owned(),
+            url: "https://example.com/".to_owned(),
+            request_body: None,
+            status: 503,
+            headers: [("content-type".to_owned(), "text/html".to_owned())].into(),
+            hash: archive.put(b"<h1>down for maintenance</h1>").unwrap(),
+        };
+
+        let cassette = TempDir::new("cassette").unwrap();
+        super::export(&archive, &[entry], cassette.path()).unwrap();
+
+        let res = Client::replay(cassette.path())
+            .unwrap()
+            .get("https://example.com/")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.text().await.unwrap(), "<h1>down for maintenance</h1>");
+    }
+
+    #[test]
+    fn missing_cassette() {
+        assert!(Client::replay("/nonexistent").is_err());
+    }
+
+    #[test]
+    fn stable_file_names() {
+        assert_eq!(
+            body_file_name(
+                &Method::GET,
+                "https://example.com/",
+                None,
+                Some("text/html")
+            ),
+            body_file_name(
+                &Method::GET,
+                "https://example.com/",
+                None,
+                Some("text/html")
+            )
+        );
+        assert_ne!(
+            body_file_name(&Method::GET, "https://example.com/", None, None),
+            body_file_name(&Method::POST, "https://example.com/", None, None)
+        );
+        assert_ne!(
+            body_file_name(&Method::POST, "https://example.com/", Some("a=1"), None),
+            body_file_name(&Method::POST, "https://example.com/", Some("a=2"), None)
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Errors that carry enough context to tell why a menu could not be
+//! refreshed, and whether trying again later is likely to help.
+
+use std::{borrow::Cow, fmt, io};
+
+use reqwest::{StatusCode, Url};
+use stor::menu::{Failure, FailureKind, Supplier};
+use time::OffsetDateTime;
+
+use crate::client::{self, Page};
+
+/// Number of characters of the response body kept in a [`ScrapeError`].
+const EXCERPT_LEN: usize = 512;
+
+/// A supplier response did not look the way we expected.
+#[derive(Debug, Clone, Default)]
+pub struct ScrapeError {
+    /// Filled in by [`crate::list_days`] if the supplier did not.
+    pub supplier: Option<Supplier>,
+    /// What we were trying to extract when things went wrong, e.g. which
+    /// element was missing.
+    pub step: Cow<'static, str>,
+    pub url: Option<Url>,
+    pub status: Option<StatusCode>,
+    /// The beginning of the response body.
+    pub excerpt: Option<String>,
+}
+
+impl ScrapeError {
+    pub fn new(step: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            step: step.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Attach the response that could not be scraped.
+    #[must_use]
+    pub fn with_page(self, page: &Page) -> Self {
+        Self {
+            url: Some(page.url.clone()),
+            status: Some(page.status),
+            excerpt: Some(excerpt(&page.body)),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn kind(&self) -> FailureKind {
+        self.status.map_or(FailureKind::Permanent, status_kind)
+    }
+}
+
+impl fmt::Display for ScrapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(supplier) = self.supplier {
+            write!(f, "{supplier}: ")?;
+        }
+
+        write!(f, "{}", self.step)?;
+
+        match (&self.url, self.status) {
+            (Some(url), Some(status)) => write!(f, " ({url} responded with {status})"),
+            (Some(url), None) => write!(f, " ({url})"),
+            (None, Some(status)) => write!(f, " (status {status})"),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ScrapeError {}
+
+fn excerpt(body: &str) -> String {
+    let body = body.trim();
+
+    match body.char_indices().nth(EXCERPT_LEN) {
+        Some((i, _)) => format!("{}…", &body[..i]),
+        None => body.to_owned(),
+    }
+}
+
+fn status_kind(status: StatusCode) -> FailureKind {
+    if client::is_transient(status) {
+        FailureKind::Transient
+    } else {
+        FailureKind::Permanent
+    }
+}
+
+fn reqwest_kind(e: &reqwest::Error) -> FailureKind {
+    if e.is_timeout() || e.is_connect() || e.is_request() || e.is_body() {
+        FailureKind::Transient
+    } else if let Some(status) = e.status() {
+        status_kind(status)
+    } else {
+        FailureKind::Permanent
+    }
+}
+
+/// Describe why refreshing a menu failed, so that it can be stored
+/// alongside the menu.
+#[must_use]
+pub fn failure(e: &anyhow::Error) -> Failure {
+    let mut failure = Failure {
+        kind: FailureKind::Permanent,
+        reason: format!("{e:#}"),
+        url: None,
+        status: None,
+        excerpt: None,
+        failed_at: OffsetDateTime::now_utc(),
+    };
+
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<ScrapeError>() {
+            failure.kind = e.kind();
+            failure.url = e.url.as_ref().map(ToString::to_string);
+            failure.status = e.status.map(|s| s.as_u16());
+            failure.excerpt = e.excerpt.clone();
+            break;
+        } else if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            failure.kind = reqwest_kind(e);
+            failure.url = e.url().map(ToString::to_string);
+            failure.status = e.status().map(|s| s.as_u16());
+            break;
+        } else if let Some(client::Error::Reqwest(e)) = cause.downcast_ref::<client::Error>() {
+            failure.kind = reqwest_kind(e);
+            failure.url = e.url().map(ToString::to_string);
+            failure.status = e.status().map(|s| s.as_u16());
+            break;
+        } else if cause.is::<io::Error>() || cause.is::<sqlx::Error>() {
+            // our own fault, not the supplier's
+            failure.kind = FailureKind::Transient;
+            break;
+        }
+    }
+
+    failure
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::StatusCode;
+    use stor::menu::FailureKind;
+
+    use crate::Error;
+
+    use super::ScrapeError;
+
+    #[test]
+    fn excerpt() {
+        assert_eq!(super::excerpt("  <html></html>\n"), "<html></html>");
+
+        let long = "å".repeat(1000);
+        let excerpt = super::excerpt(&long);
+        assert_eq!(excerpt.chars().count(), super::EXCERPT_LEN + 1);
+        assert!(excerpt.ends_with('…'));
+    }
+
+    #[test]
+    fn failure() {
+        let e = ScrapeError {
+            status: Some(StatusCode::SERVICE_UNAVAILABLE),
+            ..ScrapeError::new("no menu found")
+        };
+        let failure = super::failure(&anyhow::Error::new(e).context("listing days"));
+        assert_eq!(failure.kind, FailureKind::Transient);
+        assert_eq!(failure.status, Some(503));
+        assert_eq!(
+            failure.reason,
+            "listing days: no menu found (status 503 Service Unavailable)"
+        );
+
+        let e = ScrapeError {
+            status: Some(StatusCode::NOT_FOUND),
+            ..ScrapeError::new("no menu found")
+        };
+        assert_eq!(super::failure(&e.into()).kind, FailureKind::Permanent);
+
+        let e = anyhow::Error::new(sqlx::Error::PoolTimedOut).context("failed to save menu");
+        assert_eq!(super::failure(&e).kind, FailureKind::Transient);
+
+        let failure = super::failure(&Error::MenuNotFound.into());
+        assert_eq!(failure.kind, FailureKind::Permanent);
+        assert_eq!(failure.reason, "menu not found");
+    }
+}
@@ -0,0 +1,71 @@
+//! `munin index --dry-run`: run the scrapers without a database and print
+//! what they return, e.g. to compare the output of two versions of a scraper.
+
+use std::io::{self, Write};
+
+use serde::Serialize;
+use stor::config::Config;
+use time::{Duration, OffsetDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::info;
+
+use crate::supplier::ListDays;
+
+use super::Args;
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    /// A single (pretty-printed) JSON array.
+    Json,
+    /// One JSON value per line.
+    Ndjson,
+}
+
+fn print<T: Serialize>(items: &[T], format: Format) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+
+    match format {
+        Format::Json => serde_json::to_writer_pretty(&mut stdout, items)?,
+        Format::Ndjson => {
+            for item in items {
+                serde_json::to_writer(&mut stdout, item)?;
+                writeln!(stdout)?;
+            }
+        }
+    }
+
+    stdout.flush()
+}
+
+/// Print the days of the menu given by `--supplier` and `--reference`, or
+/// else the menus that `--load-menus` would load.
+pub async fn run(mut opt: Args, config: &Config) -> anyhow::Result<()> {
+    opt.configure(config);
+
+    let (client, _) = opt.client(config)?;
+
+    match (opt.filter.supplier, opt.filter.reference.as_deref()) {
+        (Some(supplier), Some(reference)) => {
+            let start = OffsetDateTime::now_utc().to_timezone(crate::TZ).date();
+            let end = start + Duration::days(opt.days.into());
+
+            let ListDays { menu, mut days } =
+                crate::list_days(&client, supplier, reference, start..=end).await?;
+            days.sort();
+
+            info!(?menu, days = days.len(), "scraped menu");
+            print(&days, opt.format)?;
+        }
+        _ => {
+            let mut menus = super::list_menus(&client, config, &opt.filter).await?;
+            menus.sort_by(|a, b| {
+                (a.supplier, &a.supplier_reference).cmp(&(b.supplier, &b.supplier_reference))
+            });
+
+            info!(menus = menus.len(), "listed menus");
+            print(&menus, opt.format)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,375 @@
+//! Notice when a supplier stops working, e.g. because its markup changed, by
+//! comparing the runs of the last day or so to the runs before them.
+
+use serde::Serialize;
+use sqlx::PgConnection;
+use stor::{
+    menu::Supplier,
+    run::{IndexRun, SupplierRun, MAX_FAILURE_REASONS},
+};
+use time::{Duration, OffsetDateTime};
+use tracing::{error, info};
+use uuid::Uuid;
+
+#[derive(Debug, clap::Args)]
+#[group(id = "health")]
+pub struct Args {
+    /// The runs that started this many hours before a run, up to and
+    /// including it, are checked together.
+    #[arg(long, default_value = "24")]
+    window_hours: i64,
+
+    /// The checked runs are compared to the runs of this many days before
+    /// them.
+    #[arg(long, default_value = "7")]
+    baseline_days: i64,
+
+    /// A supplier has regressed if its success rate or meals per menu falls
+    /// below this fraction of the baseline.
+    #[arg(long, default_value = "0.5")]
+    regression_threshold: f64,
+
+    /// Suppliers with fewer menus than this in the checked runs, or in the
+    /// baseline, are not checked.
+    #[arg(long, default_value = "10")]
+    min_menus: i32,
+
+    /// POST regressions as JSON to this URL.
+    #[arg(long, env)]
+    alert_webhook_url: Option<String>,
+}
+
+/// The results of a supplier, summed over one or more runs.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Rates {
+    pub menus: i32,
+    /// The fraction of the menus that were refreshed.
+    pub success_rate: f64,
+    /// The average number of meals on the menus that were refreshed.
+    pub meals_per_menu: f64,
+}
+
+impl Rates {
+    fn new<'a>(runs: impl IntoIterator<Item = &'a SupplierRun>) -> Self {
+        let (menus, successful, meals) = runs.into_iter().fold((0, 0, 0), |acc, s| {
+            (acc.0 + s.total, acc.1 + s.successful, acc.2 + s.meals)
+        });
+
+        Self {
+            menus,
+            success_rate: ratio(successful as f64, menus as f64),
+            meals_per_menu: ratio(meals as f64, successful as f64),
+        }
+    }
+}
+
+/// The results of `supplier` in `runs`.
+fn results<'a>(runs: &[&'a IndexRun], supplier: Supplier) -> Vec<&'a SupplierRun> {
+    runs.iter()
+        .flat_map(|r| &r.suppliers)
+        .filter(|s| s.supplier == supplier)
+        .collect()
+}
+
+fn ratio(a: f64, b: f64) -> f64 {
+    if b > 0. {
+        a / b
+    } else {
+        0.
+    }
+}
+
+/// A supplier that did notably worse in the checked runs than in the
+/// baseline.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Regression {
+    pub supplier: Supplier,
+    pub run: Rates,
+    pub baseline: Rates,
+    /// Some of the reasons the menus of the supplier failed in the checked
+    /// runs, the most recent first.
+    pub failure_reasons: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Alert<'a> {
+    run_id: Uuid,
+    regressions: &'a [Regression],
+}
+
+impl Args {
+    fn window(&self) -> Duration {
+        Duration::hours(self.window_hours)
+    }
+
+    /// The suppliers that regressed in the runs of the window that ends at
+    /// `at`, compared to the baseline before it. `runs` must include both.
+    fn regressions(&self, runs: &[IndexRun], at: OffsetDateTime) -> Vec<Regression> {
+        let window_start = at - self.window();
+        let baseline_start = window_start - Duration::days(self.baseline_days);
+
+        let (mut window, baseline): (Vec<_>, Vec<_>) = runs
+            .iter()
+            .filter(|r| r.started_at >= baseline_start && r.started_at <= at)
+            .partition(|r| r.started_at >= window_start);
+        window.sort_by_key(|r| std::cmp::Reverse(r.started_at));
+
+        let mut suppliers = window
+            .iter()
+            .flat_map(|r| &r.suppliers)
+            .map(|s| s.supplier)
+            .collect::<Vec<_>>();
+        suppliers.sort();
+        suppliers.dedup();
+
+        suppliers
+            .into_iter()
+            .filter_map(|supplier| {
+                let current = results(&window, supplier);
+                let rates = Rates::new(current.iter().copied());
+                let baseline = Rates::new(results(&baseline, supplier));
+                if rates.menus < self.min_menus || baseline.menus < self.min_menus {
+                    return None;
+                }
+
+                let regressed = rates.success_rate
+                    < baseline.success_rate * self.regression_threshold
+                    || rates.meals_per_menu < baseline.meals_per_menu * self.regression_threshold;
+                if !regressed {
+                    return None;
+                }
+
+                let mut failure_reasons = Vec::new();
+                for reason in current.iter().flat_map(|s| &s.failure_reasons) {
+                    if failure_reasons.len() < MAX_FAILURE_REASONS
+                        && !failure_reasons.contains(reason)
+                    {
+                        failure_reasons.push(reason.clone());
+                    }
+                }
+
+                Some(Regression {
+                    supplier,
+                    run: rates,
+                    baseline,
+                    failure_reasons,
+                })
+            })
+            .collect()
+    }
+
+    /// The suppliers that regressed in the window that ends at `at`.
+    async fn regressions_at(
+        &self,
+        conn: &mut PgConnection,
+        at: OffsetDateTime,
+    ) -> anyhow::Result<Vec<Regression>> {
+        let from = at - self.window() - Duration::days(self.baseline_days);
+        let runs = IndexRun::between(conn, from, at).await?;
+
+        Ok(self.regressions(&runs, at))
+    }
+
+    /// The suppliers that had regressed when the run before `run` was
+    /// checked.
+    async fn previous(
+        &self,
+        conn: &mut PgConnection,
+        run: &IndexRun,
+    ) -> anyhow::Result<Vec<Regression>> {
+        let previous: Option<OffsetDateTime> =
+            sqlx::query_scalar("SELECT MAX(started_at) FROM index_runs WHERE started_at < $1")
+                .bind(run.started_at)
+                .fetch_one(&mut *conn)
+                .await?;
+
+        match previous {
+            Some(at) => self.regressions_at(conn, at).await,
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Check the window that ends with `run`, which must have been recorded,
+    /// and log and send alerts for the suppliers that regressed, unless they
+    /// had already regressed when the run before it was checked.
+    pub(crate) async fn check(
+        &self,
+        conn: &mut PgConnection,
+        run: &IndexRun,
+    ) -> anyhow::Result<Vec<Regression>> {
+        let regressions = self.regressions_at(conn, run.started_at).await?;
+        let previous = self.previous(conn, run).await?;
+
+        for r in &previous {
+            if !regressions.iter().any(|n| n.supplier == r.supplier) {
+                info!(supplier = %r.supplier, "supplier recovered");
+            }
+        }
+
+        let new = regressions
+            .iter()
+            .filter(|r| !previous.iter().any(|p| p.supplier == r.supplier))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for r in &new {
+            error!(
+                alert = "supplier_regressed",
+                supplier = %r.supplier,
+                run = %run.id,
+                menus = r.run.menus,
+                success_rate = r.run.success_rate,
+                baseline_success_rate = r.baseline.success_rate,
+                meals_per_menu = r.run.meals_per_menu,
+                baseline_meals_per_menu = r.baseline.meals_per_menu,
+                failure_reasons = ?r.failure_reasons,
+                "supplier regressed"
+            );
+        }
+
+        if let (Some(url), false) = (&self.alert_webhook_url, new.is_empty()) {
+            let alert = Alert {
+                run_id: run.id,
+                regressions: &new,
+            };
+
+            // an alert that cannot be delivered should not stop the indexing
+            if let Err(e) = send(url, &alert).await {
+                error!("failed to send alert: {e:#}");
+            }
+        }
+
+        Ok(regressions)
+    }
+}
+
+async fn send(url: &str, alert: &Alert<'_>) -> reqwest::Result<()> {
+    reqwest::Client::builder()
+        .user_agent(crate::USER_AGENT)
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?
+        .post(url)
+        .json(alert)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::PgPool;
+    use stor::menu::FailureKind;
+    use time::{Duration, OffsetDateTime};
+
+    use super::*;
+
+    fn args() -> Args {
+        Args {
+            window_hours: 24,
+            baseline_days: 7,
+            regression_threshold: 0.5,
+            min_menus: 10,
+            alert_webhook_url: None,
+        }
+    }
+
+    /// A run that started `hours_ago`, where `successful` of `total` menus,
+    /// with `meals` meals each, were refreshed.
+    fn run(
+        hours_ago: i64,
+        supplier: Supplier,
+        total: i32,
+        successful: i32,
+        meals: u64,
+    ) -> IndexRun {
+        let mut run = IndexRun::new(OffsetDateTime::now_utc() - Duration::hours(hours_ago));
+        let s = run.supplier_mut(supplier);
+
+        for _ in 0..successful {
+            s.success(meals, 0, 0);
+        }
+        for _ in successful..total {
+            s.failure(FailureKind::Permanent, "no days found");
+        }
+
+        run
+    }
+
+    #[test]
+    fn regressions() {
+        let now = OffsetDateTime::now_utc();
+        let args = args();
+        let with_baseline = |window: Vec<IndexRun>| {
+            let mut runs = vec![
+                run(30, Supplier::Skolmaten, 20, 19, 5),
+                run(50, Supplier::Skolmaten, 20, 20, 5),
+                // too old to be part of the baseline
+                run(24 * 8 + 30, Supplier::Skolmaten, 20, 0, 5),
+            ];
+            runs.extend(window);
+            args.regressions(&runs, now)
+        };
+
+        assert!(with_baseline(vec![run(1, Supplier::Skolmaten, 20, 17, 5)]).is_empty());
+
+        let regressions = with_baseline(vec![run(1, Supplier::Skolmaten, 20, 0, 5)]);
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].supplier, Supplier::Skolmaten);
+        assert_eq!(regressions[0].run.success_rate, 0.);
+        assert_eq!(regressions[0].baseline.success_rate, 39. / 40.);
+        assert_eq!(regressions[0].failure_reasons, ["no days found"]);
+
+        // the menus were fetched, but the meals could no longer be found
+        let regressions = with_baseline(vec![run(1, Supplier::Skolmaten, 20, 20, 1)]);
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].run.meals_per_menu, 1.);
+
+        // runs with few menus each are checked together
+        let regressions = with_baseline(
+            (1..5)
+                .map(|h| run(h, Supplier::Skolmaten, 5, 0, 5))
+                .collect(),
+        );
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].run.menus, 20);
+
+        // too few menus to tell
+        assert!(with_baseline(vec![run(1, Supplier::Skolmaten, 5, 0, 5)]).is_empty());
+        assert!(with_baseline(vec![run(1, Supplier::Sodexo, 20, 0, 5)]).is_empty());
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn check(pool: PgPool) -> anyhow::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let args = args();
+
+        for i in 0..3 {
+            run(30 + i, Supplier::Mpi, 20, 20, 5)
+                .insert(&mut conn)
+                .await?;
+        }
+
+        let broken = run(2, Supplier::Mpi, 20, 2, 5);
+        broken.insert(&mut conn).await?;
+
+        // the run is not part of its own baseline, even though it is the latest
+        let regressions = args.check(&mut conn, &broken).await?;
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].baseline.success_rate, 1.);
+        assert!(args.previous(&mut conn, &broken).await?.is_empty());
+
+        // still regressed, but only alerted about once, even by another
+        // process
+        let still_broken = run(1, Supplier::Mpi, 20, 2, 5);
+        still_broken.insert(&mut conn).await?;
+
+        let regressions = args.check(&mut conn, &still_broken).await?;
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].run.menus, 40);
+        assert_eq!(args.previous(&mut conn, &still_broken).await?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -1,13 +1,17 @@
-use std::{str::FromStr, sync::Arc};
+use std::{future::Future, str::FromStr, sync::Arc};
 
-use anyhow::Context;
-use futures::{Stream, StreamExt, TryStreamExt};
+use anyhow::{bail, Context};
+use futures::{future, stream, Stream, StreamExt, TryStreamExt};
 use geo::VincentyDistance;
 use milli::{heed::RoTxn, AscDesc, FieldsIdsMap, TermsMatchingStrategy};
 use opentelemetry::propagation::Injector;
-use reqwest::Client;
 use sqlx::{Acquire, PgConnection, PgExecutor, PgPool};
-use stor::{Day, Menu};
+use stor::{
+    config::Config,
+    menu::{Failure, FailureKind, Supplier},
+    run::IndexRun,
+    Day, Menu,
+};
 use time::{Date, Duration, OffsetDateTime};
 use time_tz::OffsetDateTimeExt;
 use tonic::{
@@ -19,14 +23,20 @@ use tonic::{
 use tracing::{debug, error, info, instrument, warn, Span};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 use trast_proto::{trast_client::TrastClient, NerInput};
+use uuid::Uuid;
 
 use crate::{
+    archive::Archive,
+    client::Client,
     geosearch::{self, Hit},
+    metrics,
     supplier::ListDays,
     Result,
 };
 
-mod meili;
+pub mod dry_run;
+pub mod health;
+pub mod serve;
 
 const CONVERGENCE_LIMIT_M: f64 = 1000.;
 
@@ -45,32 +55,104 @@ pub struct Args {
     #[arg(long, default_value = "90")]
     days: u32,
 
+    /// How many menus to process at once. Requests are additionally limited
+    /// per host, see [`crate::supplier::Supplier::rate_limit`].
     #[arg(long, default_value = "50")]
     concurrent: usize,
 
     #[arg(long, short = 'l')]
     menu_limit: Option<i64>,
 
-    /// All menus that were updated earlier than this will be selected.
+    /// How often to check menus that have not been checked successfully
+    /// since their last change. Other menus are checked more often if their
+    /// days change often, and less often if they don't.
     #[arg(long, default_value = "86400")]
     max_age_secs: i64,
 
     #[arg(long, default_value = "3600")]
     backoff_secs: i64,
 
-    /// If provided, the menus will be inserted into the given
-    /// MeiliSearch instance.
-    #[arg(long, env)]
-    meili_url: Option<String>,
+    /// The shortest time between two checks of a menu that changes often.
+    #[arg(long, default_value = "3600")]
+    min_check_interval_secs: i64,
 
-    #[arg(long, env, hide_env_values = true, default_value = "")]
-    meili_key: String,
+    /// The longest time between two checks of a menu that rarely changes.
+    #[arg(long, default_value = "604800")]
+    max_check_interval_secs: i64,
+
+    /// How long the menus claimed by this worker are reserved for it. The
+    /// lease is renewed while the worker is refreshing, so if the worker
+    /// crashes, other workers pick up its menus once this has passed.
+    #[arg(long, default_value = "600")]
+    lease_secs: i64,
 
     #[arg(long, env)]
     trast_url: Option<String>,
+
+    /// Scrape without a database and print the days of the menu given by
+    /// `--supplier` and `--reference`, or else the menus that would be
+    /// loaded, to stdout.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// How to print the output of `--dry-run`.
+    #[arg(long, value_enum, default_value = "json", requires = "dry_run")]
+    format: dry_run::Format,
+
+    #[command(flatten)]
+    filter: Filter,
+
+    #[command(flatten)]
+    archive: crate::archive::Args,
+
+    #[command(flatten)]
+    health: health::Args,
+
+    #[command(flatten)]
+    meili: crate::meili::Args,
+}
+
+/// Narrows down which menus are loaded and refreshed, e.g. to re-index the
+/// menus of a supplier after fixing its scraper.
+#[derive(Debug, Default, clap::Args)]
+pub struct Filter {
+    /// Only load and refresh the menus of this supplier.
+    #[arg(long)]
+    supplier: Option<Supplier>,
+
+    /// Only refresh the menu with this id.
+    #[arg(long)]
+    menu: Option<Uuid>,
+
+    /// Only load and refresh the menus with this supplier reference.
+    #[arg(long)]
+    reference: Option<String>,
+
+    /// Only refresh the menus that failed the last time they were checked.
+    #[arg(long)]
+    failing_only: bool,
+
+    /// Refresh the selected menus even if they are not expired, ignoring
+    /// `--max-age-secs` and `--backoff-secs`.
+    #[arg(long)]
+    force: bool,
+
+    /// Suppliers disabled in the configuration, whose menus are neither
+    /// loaded nor refreshed.
+    #[arg(skip)]
+    disabled: Vec<Supplier>,
 }
 
-pub const INSERTION_BATCH_SIZE: usize = 10_000;
+impl Filter {
+    /// Whether a newly listed menu should be loaded.
+    fn loads(&self, menu: &Menu) -> bool {
+        !self.disabled.contains(&menu.supplier)
+            && (self.supplier.is_none() || self.supplier == Some(menu.supplier))
+            && (self.menu.is_none() || self.menu == Some(menu.id))
+            && (self.reference.is_none()
+                || self.reference.as_ref() == Some(&menu.supplier_reference))
+    }
+}
 
 struct SearchTxn<'a> {
     index: &'a milli::Index,
@@ -103,9 +185,33 @@ impl<'a> SearchTxn<'a> {
     }
 }
 
-async fn load_menus(conn: &mut PgConnection) -> anyhow::Result<()> {
-    let menus = crate::list_menus(4).await?;
+/// List the menus that `filter` loads.
+async fn list_menus(
+    client: &Client,
+    config: &Config,
+    filter: &Filter,
+) -> anyhow::Result<Vec<Menu>> {
+    let mut menus = match filter.supplier {
+        Some(s) if filter.disabled.contains(&s) => Vec::new(),
+        Some(s) => {
+            crate::supplier::get(s)?
+                .list_menus(&client.scoped(s, None))
+                .await?
+        }
+        None => crate::list_menus(client, config, 4).await?,
+    };
+    menus.retain(|m| filter.loads(m));
 
+    Ok(menus)
+}
+
+async fn load_menus(
+    conn: &mut PgConnection,
+    client: &Client,
+    config: &Config,
+    filter: &Filter,
+) -> anyhow::Result<()> {
+    let menus = list_menus(client, config, filter).await?;
     let mut txn = conn.begin().await?;
 
     for menu in menus {
@@ -119,6 +225,7 @@ async fn load_menus(conn: &mut PgConnection) -> anyhow::Result<()> {
             created_at: _,
             checked_at: _,
             consecutive_failures: _,
+            last_failure: _,
         } = menu;
 
         assert!(osm_id.is_none());
@@ -155,99 +262,262 @@ async fn load_menus(conn: &mut PgConnection) -> anyhow::Result<()> {
     Ok(txn.commit().await?)
 }
 
-fn get_expired<'a>(
-    conn: impl PgExecutor<'a> + 'a,
+/// When menus are checked. Every menu has its own check interval, which is
+/// halved (down to `min_interval`) whenever a check finds that its days have
+/// changed, and grows by half (up to `max_interval`) whenever they have not.
+#[derive(Debug, Clone, Copy)]
+struct Schedule {
+    /// The check interval of menus that have not been checked successfully.
     max_age: Duration,
     backoff: Duration,
-    limit: Option<i64>,
-) -> impl Stream<Item = Result<Menu>> + 'a {
-    let expires_at = OffsetDateTime::now_utc() - max_age;
+    min_interval: Duration,
+    max_interval: Duration,
+}
 
+/// Lease up to `limit` menus that were due before `now`, so that several
+/// workers can refresh menus at the same time without processing the same
+/// menus. Menus leased by other workers are skipped until their lease
+/// expires.
+async fn claim_expired(
+    pool: &PgPool,
+    now: OffsetDateTime,
+    schedule: &Schedule,
+    limit: i64,
+    filter: &Filter,
+    lease: Duration,
+    worker: Uuid,
+) -> sqlx::Result<Vec<Menu>> {
     sqlx::query_as::<_, Menu>(
         r#"
-                SELECT * FROM menus
+            UPDATE menus SET leased_by = $11, lease_expires_at = NOW() + $10
+            WHERE id IN (
+                SELECT id FROM menus
                 WHERE
-                    checked_at IS NULL OR
-                    checked_at < $1 + $2 * (2 ^ (LEAST(consecutive_failures, 4)) - 1)
+                    (lease_expires_at IS NULL OR lease_expires_at < NOW()) AND
+                    (
+                        checked_at IS NULL OR
+                        checked_at < $1 - CASE
+                            WHEN $9 THEN INTERVAL '0'
+                            WHEN consecutive_failures > 0
+                                THEN $2 - $3 * (2 ^ (LEAST(consecutive_failures, 4)) - 1)
+                            ELSE COALESCE(check_interval, $2)
+                        END
+                    ) AND
+                    ($5::supplier IS NULL OR supplier = $5) AND
+                    ($6::uuid IS NULL OR id = $6) AND
+                    ($7::text IS NULL OR supplier_reference = $7) AND
+                    (NOT $8 OR consecutive_failures > 0) AND
+                    NOT (supplier::text = ANY($12))
                 ORDER BY checked_at ASC
-                LIMIT $3
-            "#,
+                LIMIT $4
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+        "#,
     )
-    .bind(expires_at)
-    .bind(backoff)
+    .bind(now)
+    .bind(schedule.max_age)
+    .bind(schedule.backoff)
     .bind(limit)
-    .fetch(conn)
-    .map_err(Into::into)
+    .bind(filter.supplier)
+    .bind(filter.menu)
+    .bind(filter.reference.as_deref())
+    .bind(filter.failing_only)
+    .bind(filter.force)
+    .bind(lease)
+    .bind(worker)
+    .bind(
+        filter
+            .disabled
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>(),
+    )
+    .fetch_all(pool)
+    .await
 }
 
-pub async fn index(opt: Args, pool: &PgPool) -> anyhow::Result<()> {
-    let mut conn = pool.acquire().await?;
+/// Extend the leases of the menus that `worker` has claimed but not yet
+/// refreshed, so that a batch that takes longer than the lease is not picked
+/// up by other workers while it is still in progress.
+async fn renew_leases(pool: &PgPool, lease: Duration, worker: Uuid) -> sqlx::Result<u64> {
+    let res = sqlx::query("UPDATE menus SET lease_expires_at = NOW() + $1 WHERE leased_by = $2")
+        .bind(lease)
+        .bind(worker)
+        .execute(pool)
+        .await?;
 
-    let gh_pat = opt.osm_gh_pat.clone();
+    Ok(res.rows_affected())
+}
 
-    let geoindex = tokio::spawn(async move {
-        anyhow::Ok(if let Some(ref gh_pat) = gh_pat {
-            info!("building geoindex");
+/// Claim expired menus in batches of `batch`, as they are consumed, until
+/// none are left or `limit` menus have been claimed.
+fn get_expired<'a>(
+    pool: &'a PgPool,
+    opt: &'a Args,
+    batch: i64,
+    worker: Uuid,
+) -> impl Stream<Item = Result<Menu>> + 'a {
+    // menus are due relative to the start of the run, so that menus that are
+    // refreshed during the run are not picked up again, even with --force
+    let now = OffsetDateTime::now_utc();
+    let schedule = opt.schedule();
+
+    stream::try_unfold(opt.menu_limit, move |remaining| async move {
+        let limit = remaining.map_or(batch, |r| r.min(batch));
+        if limit <= 0 {
+            return anyhow::Ok(None);
+        }
 
-            match crate::geosearch::build_index(gh_pat).await {
-                Ok(index) => {
-                    let rtxn = index.inner.read_txn()?;
-                    let num_docs = index.inner.number_of_documents(&rtxn)?;
-                    info!(num_docs, "built geoindex");
-                    drop(rtxn);
-                    Some(index)
-                }
-                Err(e) => {
-                    error!("failed to build geoindex: {e}");
-                    None
-                }
+        let menus = claim_expired(
+            pool,
+            now,
+            &schedule,
+            limit,
+            &opt.filter,
+            Duration::seconds(opt.lease_secs),
+            worker,
+        )
+        .await?;
+        if menus.is_empty() {
+            return anyhow::Ok(None);
+        }
+
+        let remaining = remaining.map(|r| r - menus.len() as i64);
+        anyhow::Ok(Some((stream::iter(menus).map(Ok), remaining)))
+    })
+    .try_flatten()
+}
+
+impl Args {
+    /// Fill in what was not given on the command line from `config`.
+    fn configure(&mut self, config: &Config) {
+        self.meili.configure(config);
+
+        self.filter.disabled = config
+            .suppliers
+            .iter()
+            .filter(|(_, s)| !s.enabled)
+            .map(|(id, _)| *id)
+            .collect();
+    }
+
+    fn schedule(&self) -> Schedule {
+        Schedule {
+            max_age: Duration::seconds(self.max_age_secs),
+            backoff: Duration::seconds(self.backoff_secs),
+            min_interval: Duration::seconds(self.min_check_interval_secs),
+            max_interval: Duration::seconds(self.max_check_interval_secs),
+        }
+    }
+
+    /// The client to scrape with, and the archive it saves the responses
+    /// to, if requested.
+    fn client(&self, config: &Config) -> anyhow::Result<(Client, Option<Arc<Archive>>)> {
+        let client = crate::http_client(config)?;
+
+        Ok(match self.archive.open()? {
+            Some(archive) => {
+                let archive = Arc::new(archive);
+                (client.with_archive(archive.clone()), Some(archive))
             }
-        } else {
-            warn!("skipping geosearch (no personal access token found)");
-            None
+            None => (client, None),
         })
-    });
-
-    if opt.load_menus {
-        load_menus(&mut conn).await?;
     }
+}
 
-    let expired = get_expired(
-        &mut conn,
-        Duration::seconds(opt.max_age_secs),
-        Duration::seconds(opt.backoff_secs),
-        opt.menu_limit,
-    );
+async fn build_geoindex(gh_pat: Option<String>) -> anyhow::Result<Option<geosearch::Index>> {
+    Ok(if let Some(ref gh_pat) = gh_pat {
+        info!("building geoindex");
+
+        match crate::geosearch::build_index(gh_pat).await {
+            Ok(index) => {
+                let rtxn = index.inner.read_txn()?;
+                let num_docs = index.inner.number_of_documents(&rtxn)?;
+                info!(num_docs, "built geoindex");
+                drop(rtxn);
+                Some(index)
+            }
+            Err(e) => {
+                error!("failed to build geoindex: {e}");
+                None
+            }
+        }
+    } else {
+        warn!("skipping geosearch (no personal access token found)");
+        None
+    })
+}
 
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(30))
-        .build()?;
-    let start = OffsetDateTime::now_utc().to_timezone(crate::TZ).date();
-    let end = start + Duration::days(opt.days.into());
+pub async fn index(mut opt: Args, config: &Config, pool: &PgPool) -> anyhow::Result<()> {
+    opt.configure(config);
+
+    let geoindex = tokio::spawn(build_geoindex(opt.osm_gh_pat.clone()));
+    let (client, _) = opt.client(config)?;
+
+    if opt.load_menus {
+        load_menus(&mut *pool.acquire().await?, &client, config, &opt.filter).await?;
+    }
 
     let geoindex = geoindex.await??;
     let search_txn = match geoindex.as_ref() {
-        Some(i) => Some(Arc::new(SearchTxn::new(i, opt.trast_url).await?)),
+        Some(i) => Some(SearchTxn::new(i, opt.trast_url.clone()).await?),
         None => None,
     };
 
-    let mut results = expired
+    let run = refresh(&opt, pool, &client, search_txn.as_ref(), future::pending()).await?;
+    let regressions = record_run(&opt, pool, &run).await?;
+
+    if opt.meili.enabled() {
+        crate::meili::sync(&opt.meili, pool).await?;
+    }
+
+    if !regressions.is_empty() {
+        bail!(
+            "suppliers regressed: {}",
+            regressions
+                .iter()
+                .map(|r| r.supplier.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Refresh the expired menus. Once `shutdown` completes, no more menus are
+/// picked up, but the ones that are being fetched are still written.
+///
+/// The results are returned as an [`IndexRun`], for the caller to record.
+async fn refresh(
+    opt: &Args,
+    pool: &PgPool,
+    client: &Client,
+    search_txn: Option<&SearchTxn<'_>>,
+    shutdown: impl Future<Output = ()>,
+) -> anyhow::Result<IndexRun> {
+    let worker = Uuid::new_v4();
+    let mut run = IndexRun::new(OffsetDateTime::now_utc());
+    let schedule = opt.schedule();
+    let shutdown = async {
+        shutdown.await;
+        info!("shutting down, no longer picking up menus");
+    };
+    let expired = get_expired(pool, opt, opt.concurrent as i64, worker).take_until(shutdown);
+    let start = OffsetDateTime::now_utc().to_timezone(crate::TZ).date();
+    let end = start + Duration::days(opt.days.into());
+
+    let results = expired
         .map(|result| {
             let client = client.clone();
-            let search_txn = search_txn.clone();
             async move {
                 match result {
                     Ok(mut menu) => {
                         debug!(?menu, "processing menu");
-                        let days = process_menu(
-                            &client,
-                            &mut menu,
-                            start,
-                            end,
-                            opt.days,
-                            search_txn.as_deref(),
-                        )
-                        .await;
+                        let days =
+                            process_menu(&client, &mut menu, start, end, opt.days, search_txn)
+                                .await;
 
                         Ok((menu, days))
                     }
@@ -257,20 +527,35 @@ pub async fn index(opt: Args, pool: &PgPool) -> anyhow::Result<()> {
         })
         .buffer_unordered(opt.concurrent);
 
-    // open a new connection since get_expired uses the current
-    let mut txn = pool.begin().await?;
-    let mut uncommitted_queries = 0usize;
-
-    let mut total = 0;
-    let mut successful = 0;
-
     let pb = indicatif::ProgressBar::new_spinner()
         .with_style(
             indicatif::ProgressStyle::with_template("{spinner} {msg} ({pos} done)").unwrap(),
         )
         .with_message("updating menus");
 
-    while let Some(res) = results.next().await {
+    // renewed well before the leases expire, in case a renewal fails
+    let lease = Duration::seconds(opt.lease_secs);
+    let renew_every = (lease.unsigned_abs() / 3).max(std::time::Duration::from_secs(1));
+    let mut renew =
+        tokio::time::interval_at(tokio::time::Instant::now() + renew_every, renew_every);
+
+    tokio::pin!(results);
+
+    loop {
+        let res = tokio::select! {
+            res = results.next() => match res {
+                Some(res) => res,
+                None => break,
+            },
+            _ = renew.tick() => {
+                if let Err(e) = renew_leases(pool, lease, worker).await {
+                    error!("failed to renew leases: {e}");
+                }
+
+                continue;
+            }
+        };
+
         pb.inc(1);
 
         let (menu, days) = match res {
@@ -278,133 +563,301 @@ pub async fn index(opt: Args, pool: &PgPool) -> anyhow::Result<()> {
             Err(_) => continue,
         };
 
-        let success = days.is_ok();
-        match days {
-            Ok(days) => {
-                for day in days {
-                    let Day { date, meals } = day;
-
-                    sqlx::query!(
-                        r#"
-                            DELETE FROM meals WHERE menu_id = $1 AND date = $2
-                        "#,
-                        menu.id,
-                        date
-                    )
-                    .execute(&mut txn)
-                    .await
-                    .context("failed to delete old meals")?;
-
-                    for meal in meals {
-                        sqlx::query!(
-                            r#"
-                                INSERT INTO meals (menu_id, date, meal)
-                                    VALUES ($1, $2, $3)
-                                    ON CONFLICT DO NOTHING
-                            "#,
-                            menu.id,
-                            date,
-                            meal
-                        )
-                        .execute(&mut txn)
-                        .await
-                        .context("failed to insert meal")?;
-
-                        uncommitted_queries += 1;
-                    }
+        let result = match days {
+            Ok(days) => save_menu(pool, &menu, days, &schedule)
+                .await
+                .context("failed to save menu"),
+            Err(e) => Err(e),
+        };
 
-                    if uncommitted_queries >= INSERTION_BATCH_SIZE {
-                        txn.commit().await?;
-                        uncommitted_queries = 0;
-                        txn = pool.begin().await?;
-                    }
+        let supplier = menu.supplier.to_string();
+
+        match result {
+            Ok(written) => {
+                metrics::DAYS_WRITTEN
+                    .with_label_values(&[&supplier])
+                    .inc_by(written.days);
+                for (change, n) in [
+                    ("seen", written.meals),
+                    ("inserted", written.inserted),
+                    ("deleted", written.deleted),
+                ] {
+                    metrics::MEALS_WRITTEN
+                        .with_label_values(&[&supplier, change])
+                        .inc_by(n);
                 }
+
+                run.supplier_mut(menu.supplier).success(
+                    written.meals,
+                    written.inserted,
+                    written.deleted,
+                );
             }
             Err(e) => {
-                warn!(supplier = ?menu.supplier, menu = %menu.id, supplier_reference = ?menu.supplier_reference, "{e}");
+                let failure = crate::error::failure(&e);
+                metrics::MENUS_FAILED
+                    .with_label_values(&[&supplier, &failure.kind.to_string()])
+                    .inc();
+                run.supplier_mut(menu.supplier)
+                    .failure(failure.kind, &failure.reason);
+                warn!(supplier = ?menu.supplier, menu = %menu.id, supplier_reference = ?menu.supplier_reference, kind = %failure.kind, "{e:#}");
+
+                if let Err(e) = update_menu(pool, &menu, Some(&failure), false, &schedule).await {
+                    error!(menu = %menu.id, "failed to record failure: {e}");
+                }
             }
         }
+    }
 
-        let now = OffsetDateTime::now_utc();
+    pb.finish_and_clear();
 
-        let Menu {
-            id,
-            title,
-            supplier: _,
-            supplier_reference: _,
-            location,
-            osm_id,
-            created_at: _,
-            checked_at: _,
-            consecutive_failures: _,
-        } = menu;
+    // menus that were claimed but not refreshed, e.g. when shutting down
+    sqlx::query("UPDATE menus SET leased_by = NULL, lease_expires_at = NULL WHERE leased_by = $1")
+        .bind(worker)
+        .execute(pool)
+        .await?;
 
-        let (longitude, latitude) = match location {
-            Some(p) => (Some(p.x()), Some(p.y())),
-            None => (None, None),
-        };
-        let osm_id = osm_id.map(|id| id.to_string());
+    run.finished_at = OffsetDateTime::now_utc();
+    info!(
+        total = run.total(),
+        successful = run.successful(),
+        "updated menus"
+    );
 
-        sqlx::query!(
-            "UPDATE menus SET
-                checked_at = $1,
-                title = $2,
-                longitude = $3,
-                latitude = $4,
-                osm_id = $5,
-                consecutive_failures = CASE
-                    WHEN $6 THEN 0
-                    ELSE consecutive_failures + 1
-                END
-            WHERE id = $7",
-            now,
-            title,
-            longitude,
-            latitude,
-            osm_id,
-            success,
-            id,
-        )
-        .execute(&mut txn)
-        .await?;
+    Ok(run)
+}
 
-        uncommitted_queries += 1;
-        total += 1;
-        if success {
-            successful += 1;
-        }
+/// Record `run`, unless no menus were checked in it, and check it for
+/// regressions (see [`health::Args::check`]).
+async fn record_run(
+    opt: &Args,
+    pool: &PgPool,
+    run: &IndexRun,
+) -> anyhow::Result<Vec<health::Regression>> {
+    if run.total() == 0 {
+        return Ok(Vec::new());
     }
 
-    pb.finish_and_clear();
+    let mut conn = pool.acquire().await?;
+    run.insert(&mut conn)
+        .await
+        .context("failed to record index run")?;
+
+    opt.health.check(&mut conn, run).await
+}
+
+/// Write the days of a menu and mark it as checked, atomically.
+async fn save_menu(
+    pool: &PgPool,
+    menu: &Menu,
+    days: Vec<Day>,
+    schedule: &Schedule,
+) -> anyhow::Result<Written> {
+    let mut txn = pool.begin().await?;
+
+    let written = write_days(&mut txn, menu.id, days, OffsetDateTime::now_utc()).await?;
+    update_menu(&mut txn, menu, None, written.changed(), schedule).await?;
     txn.commit().await?;
 
-    info!(total, successful, "updated menus");
+    Ok(written)
+}
 
-    if let Some(ref meili_url) = opt.meili_url {
-        let client = meilisearch_sdk::Client::new(meili_url, &opt.meili_key);
+/// Mark a menu as checked, recording the failure if there was one and
+/// adjusting its check interval.
+async fn update_menu<'c>(
+    executor: impl PgExecutor<'c>,
+    menu: &Menu,
+    failure: Option<&Failure>,
+    changed: bool,
+    schedule: &Schedule,
+) -> sqlx::Result<()> {
+    let now = OffsetDateTime::now_utc();
+
+    let Menu {
+        id,
+        title,
+        supplier: _,
+        supplier_reference: _,
+        location,
+        osm_id,
+        created_at: _,
+        checked_at,
+        consecutive_failures: _,
+        last_failure: _,
+    } = menu;
+
+    let (longitude, latitude) = match location {
+        Some(p) => (Some(p.x()), Some(p.y())),
+        None => (None, None),
+    };
+    let osm_id = osm_id.as_ref().map(ToString::to_string);
+    // the first check of a menu tells nothing about how often it changes
+    let changed = changed && checked_at.is_some();
+
+    sqlx::query!(
+        "UPDATE menus SET
+            checked_at = $1,
+            title = $2,
+            longitude = $3,
+            latitude = $4,
+            osm_id = $5,
+            consecutive_failures = CASE
+                WHEN $6 THEN 0
+                ELSE consecutive_failures + 1
+            END,
+            failure_kind = $8,
+            failure_reason = $9,
+            failure_url = $10,
+            failure_status = $11,
+            failure_excerpt = $12,
+            failed_at = $13,
+            leased_by = NULL,
+            lease_expires_at = NULL,
+            check_interval = CASE
+                WHEN NOT $6 THEN check_interval
+                WHEN $14 THEN GREATEST(
+                    COALESCE(check_interval, make_interval(secs => $15)) / 2,
+                    make_interval(secs => $16)
+                )
+                ELSE LEAST(
+                    COALESCE(check_interval * 1.5, make_interval(secs => $15)),
+                    make_interval(secs => $17)
+                )
+            END,
+            last_changed_at = CASE WHEN $14 THEN $1 ELSE last_changed_at END
+        WHERE id = $7",
+        now,
+        title,
+        longitude,
+        latitude,
+        osm_id,
+        failure.is_none(),
+        id,
+        failure.map(|f| f.kind) as Option<FailureKind>,
+        failure.map(|f| f.reason.as_str()),
+        failure.and_then(|f| f.url.as_deref()),
+        failure.and_then(|f| f.status).map(i32::from),
+        failure.and_then(|f| f.excerpt.as_deref()),
+        failure.map(|f| f.failed_at),
+        changed,
+        schedule.max_age.as_seconds_f64(),
+        schedule.min_interval.as_seconds_f64(),
+        schedule.max_interval.as_seconds_f64(),
+    )
+    .execute(executor)
+    .await?;
 
-        let menus_index = meili::get_or_create_index(&client, "menus").await?;
-        menus_index
-            .set_sortable_attributes(&["checked_at", "last_day", "_geo"])
-            .await?;
-        menus_index
-            .set_filterable_attributes(&["slug", "checked_at", "last_day", "_geo"])
-            .await?;
+    Ok(())
+}
 
-        let menus = sqlx::query_as::<_, meili::Menu>(
-            r#"
-                SELECT m.*, MAX(d.date) AS last_day FROM menus AS m
-                LEFT JOIN meals AS d ON d.menu_id = m.id
-                GROUP BY m.id
-            "#,
-        )
-        .fetch_all(pool)
-        .await?;
+/// What [`write_days`] did.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Written {
+    /// The number of days that were written.
+    pub days: u64,
+    /// The number of meals that were written, changed or not.
+    pub meals: u64,
+    /// The number of meals that were added.
+    pub inserted: u64,
+    /// The number of meals that were removed.
+    pub deleted: u64,
+}
 
-        meili::add_documents(&menus_index, &menus, Some("id")).await?;
+impl Written {
+    /// Whether any meals were added or removed.
+    pub fn changed(&self) -> bool {
+        self.inserted > 0 || self.deleted > 0
     }
+}
 
-    Ok(())
+/// Write the meals of some days of a menu, only deleting the meals that are
+/// no longer present. Deleting a meal cascades to its reviews, so meals that
+/// did not change must be left alone. Deleted meals are moved to
+/// `meal_history`, and the meals that are still present are marked as seen.
+///
+/// The meals are considered seen, or removed, at `seen_at`, which is the
+/// time of the fetch that they were parsed from. A meal's `last_seen` is
+/// never moved back.
+///
+/// The days are written with a fixed number of queries, regardless of how
+/// many days and meals there are.
+pub(crate) async fn write_days(
+    conn: &mut PgConnection,
+    menu_id: Uuid,
+    days: Vec<Day>,
+    seen_at: OffsetDateTime,
+) -> anyhow::Result<Written> {
+    if days.is_empty() {
+        return Ok(Written::default());
+    }
+
+    let covered = days.iter().map(|d| d.date).collect::<Vec<_>>();
+    let (dates, meals): (Vec<Date>, Vec<String>) = days
+        .into_iter()
+        .flat_map(|Day { date, meals }| meals.into_iter().map(move |meal| (date, meal)))
+        .unzip();
+
+    // counted from `removed`, as meals that are already in the history are
+    // not inserted again
+    let deleted: i64 = sqlx::query_scalar(
+        r#"
+            WITH removed AS (
+                DELETE FROM meals AS m
+                WHERE m.menu_id = $1 AND m.date = ANY($2) AND (m.date, m.meal) NOT IN (
+                    SELECT * FROM UNNEST($3::date[], $4::text[])
+                )
+                RETURNING menu_id, date, meal, first_seen, last_seen
+            ), history AS (
+                INSERT INTO meal_history (menu_id, date, meal, first_seen, last_seen, removed_at)
+                    SELECT menu_id, date, meal, first_seen, last_seen, $5 FROM removed
+                    ON CONFLICT DO NOTHING
+            )
+            SELECT COUNT(*) FROM removed
+        "#,
+    )
+    .bind(menu_id)
+    .bind(&covered)
+    .bind(&dates)
+    .bind(&meals)
+    .bind(seen_at)
+    .fetch_one(&mut *conn)
+    .await
+    .context("failed to delete old meals")?;
+
+    sqlx::query(
+        r#"
+            UPDATE meals SET last_seen = GREATEST(last_seen, $3)
+            WHERE menu_id = $1 AND date = ANY($2)
+        "#,
+    )
+    .bind(menu_id)
+    .bind(&covered)
+    .bind(seen_at)
+    .execute(&mut *conn)
+    .await
+    .context("failed to mark meals as seen")?;
+
+    let inserted = sqlx::query(
+        r#"
+            INSERT INTO meals (menu_id, date, meal, first_seen, last_seen)
+                SELECT $1, date, meal, $4, $4 FROM UNNEST($2::date[], $3::text[]) AS t (date, meal)
+                ON CONFLICT (menu_id, date, meal) DO NOTHING
+        "#,
+    )
+    .bind(menu_id)
+    .bind(&dates)
+    .bind(&meals)
+    .bind(seen_at)
+    .execute(&mut *conn)
+    .await
+    .context("failed to insert meals")?;
+
+    Ok(Written {
+        days: covered.len() as u64,
+        meals: meals.len() as u64,
+        inserted: inserted.rows_affected(),
+        deleted: deleted as u64,
+    })
 }
 
 #[instrument(skip(client, menu, search_txn), fields(menu = %menu.id))]
@@ -417,8 +870,12 @@ async fn process_menu(
     search_txn: Option<&SearchTxn<'_>>,
 ) -> Result<Vec<Day>> {
     let days = if num_days > 0 {
+        let timer = metrics::SCRAPE_DURATION
+            .with_label_values(&[&menu.supplier.to_string()])
+            .start_timer();
         let ListDays { days, menu: patch } =
             crate::list_days(client, menu.supplier, &menu.supplier_reference, start..=end).await?;
+        timer.observe_duration();
 
         menu.patch(patch);
 
@@ -487,17 +944,25 @@ async fn process_menu(
             })
             .transpose()?
         {
-            if let Some(location) = menu.location {
+            let result = if let Some(location) = menu.location {
                 if menu.osm_id.is_some()
                     || location.vincenty_distance(&hit.coordinates)? < CONVERGENCE_LIMIT_M
                 {
                     menu.osm_id = Some(hit.id);
+                    "match"
+                } else {
+                    "too_far"
                 }
             } else {
                 menu.location = Some(hit.coordinates);
                 menu.osm_id = Some(hit.id);
-            }
-        };
+                "match"
+            };
+
+            metrics::GEOSEARCH.with_label_values(&[result]).inc();
+        } else {
+            metrics::GEOSEARCH.with_label_values(&["none"]).inc();
+        }
     }
 
     Ok(days)
@@ -522,3 +987,525 @@ impl<'a> Injector for MetadataInjector<'a> {
         }
     }
 }
+
+/// Insert `menu` along with its timestamps, failures and location, for tests
+/// that need menus in the database.
+#[cfg(test)]
+pub async fn insert_menu(conn: impl PgExecutor<'_>, menu: &Menu) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO menus (id, title, supplier, supplier_reference, longitude, latitude, created_at, checked_at, consecutive_failures) VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, NOW()), $8, $9)",
+    )
+    .bind(menu.id)
+    .bind(&menu.title)
+    .bind(menu.supplier)
+    .bind(&menu.supplier_reference)
+    .bind(menu.location.map(|p| p.x()))
+    .bind(menu.location.map(|p| p.y()))
+    .bind(menu.created_at)
+    .bind(menu.checked_at)
+    .bind(menu.consecutive_failures)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use futures::TryStreamExt;
+    use sqlx::PgPool;
+    use stor::{
+        config::{Config, SupplierConfig},
+        menu::{FailureKind, Supplier},
+        Day, Menu,
+    };
+    use time::{
+        macros::{date, datetime},
+        Date, Duration, OffsetDateTime,
+    };
+    use uuid::Uuid;
+
+    use super::Filter;
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn write_day_keeps_reviews(pool: PgPool) -> anyhow::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let menu = Menu::from_supplier(Supplier::Skolmaten, "123", "School");
+        let date = date!(2023 - 02 - 08);
+
+        super::insert_menu(&mut conn, &menu).await?;
+
+        let meals = vec!["Pannkakor".to_owned(), "Fisk Björkeby".to_owned()];
+        super::write_days(
+            &mut conn,
+            menu.id,
+            vec![Day::new(date, meals)],
+            OffsetDateTime::now_utc(),
+        )
+        .await?;
+
+        sqlx::query(
+            "INSERT INTO reviews (id, author, menu_id, date, meal, rating) VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(Uuid::new_v4())
+        .bind(menu.id)
+        .bind(date)
+        .bind("Pannkakor")
+        .bind(5)
+        .execute(&mut conn)
+        .await?;
+
+        let meals = vec!["Pannkakor".to_owned(), "Köttbullar".to_owned()];
+        super::write_days(
+            &mut conn,
+            menu.id,
+            vec![Day::new(date, meals)],
+            OffsetDateTime::now_utc(),
+        )
+        .await?;
+
+        let mut stored: Vec<String> =
+            sqlx::query_scalar("SELECT meal FROM meals WHERE menu_id = $1 AND date = $2")
+                .bind(menu.id)
+                .bind(date)
+                .fetch_all(&mut conn)
+                .await?;
+        stored.sort();
+        assert_eq!(stored, ["Köttbullar", "Pannkakor"]);
+
+        let reviews: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reviews")
+            .fetch_one(&mut conn)
+            .await?;
+        assert_eq!(reviews, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn write_day_keeps_history(pool: PgPool) -> anyhow::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let menu = Menu::from_supplier(Supplier::Skolmaten, "123", "School");
+        let date = date!(2023 - 02 - 10);
+
+        super::insert_menu(&mut conn, &menu).await?;
+
+        let announced = vec!["Fiskgratäng".to_owned(), "Pannkakor".to_owned()];
+        super::write_days(
+            &mut conn,
+            menu.id,
+            vec![Day::new(date, announced.clone())],
+            OffsetDateTime::now_utc(),
+        )
+        .await?;
+
+        let monday: OffsetDateTime = sqlx::query_scalar("SELECT NOW()")
+            .fetch_one(&mut conn)
+            .await?;
+
+        let served = vec!["Köttbullar".to_owned(), "Pannkakor".to_owned()];
+        super::write_days(
+            &mut conn,
+            menu.id,
+            vec![Day::new(date, served.clone())],
+            OffsetDateTime::now_utc(),
+        )
+        .await?;
+
+        let now = OffsetDateTime::now_utc();
+        let days_at = |at| stor::meal::days_at(&pool, menu.id, date..=date, at);
+
+        assert_eq!(days_at(monday).await?, [Day::new(date, announced)]);
+        assert_eq!(days_at(now).await?, [Day::new(date, served)]);
+        assert!(days_at(now - Duration::days(1)).await?.is_empty());
+
+        Ok(())
+    }
+
+    /// The references of the menus `munin index <args>` would refresh.
+    async fn expired(pool: &PgPool, args: &[&str]) -> anyhow::Result<Vec<String>> {
+        expired_with(pool, args, &Config::default()).await
+    }
+
+    /// Like [`expired`], with `config`.
+    async fn expired_with(
+        pool: &PgPool,
+        args: &[&str],
+        config: &Config,
+    ) -> anyhow::Result<Vec<String>> {
+        #[derive(clap::Parser)]
+        struct Opt {
+            #[command(flatten)]
+            index: super::Args,
+        }
+
+        let mut opt = Opt::try_parse_from(["index"].iter().chain(args))?;
+        opt.index.configure(config);
+        let mut references = super::get_expired(pool, &opt.index, 2, Uuid::new_v4())
+            .map_ok(|m| m.supplier_reference)
+            .try_collect::<Vec<_>>()
+            .await?;
+        references.sort();
+
+        sqlx::query("UPDATE menus SET leased_by = NULL, lease_expires_at = NULL")
+            .execute(pool)
+            .await?;
+
+        Ok(references)
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn get_expired_filter(pool: PgPool) -> anyhow::Result<()> {
+        let now = OffsetDateTime::now_utc();
+        let menus = [
+            (Supplier::Skolmaten, "fresh", Some(now), 0),
+            (Supplier::Skolmaten, "failing", Some(now), 1),
+            (Supplier::Matilda, "new", None, 0),
+        ];
+
+        for (supplier, reference, checked_at, consecutive_failures) in menus {
+            let menu = Menu {
+                checked_at,
+                consecutive_failures,
+                ..Menu::from_supplier(supplier, reference, "School")
+            };
+            super::insert_menu(&pool, &menu).await?;
+        }
+
+        assert_eq!(expired(&pool, &[]).await?, ["new"]);
+        assert_eq!(
+            expired(&pool, &["--force"]).await?,
+            ["failing", "fresh", "new"]
+        );
+        assert_eq!(
+            expired(&pool, &["--force", "--supplier", "skolmaten"]).await?,
+            ["failing", "fresh"]
+        );
+        assert_eq!(
+            expired(&pool, &["--force", "--failing-only"]).await?,
+            ["failing"]
+        );
+
+        let mut config = Config::default();
+        config.suppliers.insert(
+            Supplier::Matilda,
+            SupplierConfig {
+                enabled: false,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            expired_with(&pool, &["--force"], &config).await?,
+            ["failing", "fresh"]
+        );
+        let id = Menu::from_supplier(Supplier::Skolmaten, "fresh", "")
+            .id
+            .to_string();
+        assert_eq!(
+            expired(&pool, &["--force", "--menu", &id]).await?,
+            ["fresh"]
+        );
+        assert_eq!(
+            expired(&pool, &["--reference", "fresh"]).await?,
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            expired(&pool, &["--force", "--menu-limit", "1"])
+                .await?
+                .len(),
+            1
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn claim_expired_skips_leased(pool: PgPool) -> anyhow::Result<()> {
+        let menu = Menu::from_supplier(Supplier::Skolmaten, "123", "School");
+        super::insert_menu(&pool, &menu).await?;
+
+        let filter = Filter::default();
+        let schedule = super::Schedule {
+            max_age: Duration::ZERO,
+            backoff: Duration::ZERO,
+            min_interval: Duration::ZERO,
+            max_interval: Duration::ZERO,
+        };
+        let claim = |worker| {
+            super::claim_expired(
+                &pool,
+                OffsetDateTime::now_utc(),
+                &schedule,
+                10,
+                &filter,
+                Duration::minutes(10),
+                worker,
+            )
+        };
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(claim(a).await?.len(), 1);
+        assert!(claim(b).await?.is_empty());
+
+        // a crashed
+        sqlx::query("UPDATE menus SET lease_expires_at = NOW() - INTERVAL '1 second'")
+            .execute(&pool)
+            .await?;
+        assert_eq!(claim(b).await?.len(), 1);
+
+        let leased_by: Option<Uuid> = sqlx::query_scalar("SELECT leased_by FROM menus")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(leased_by, Some(b));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn renew_leases(pool: PgPool) -> anyhow::Result<()> {
+        for reference in ["123", "456"] {
+            let menu = Menu::from_supplier(Supplier::Skolmaten, reference, "School");
+            super::insert_menu(&pool, &menu).await?;
+        }
+
+        let filter = Filter::default();
+        let schedule = super::Schedule {
+            max_age: Duration::ZERO,
+            backoff: Duration::ZERO,
+            min_interval: Duration::ZERO,
+            max_interval: Duration::ZERO,
+        };
+        let claim = |worker| {
+            super::claim_expired(
+                &pool,
+                OffsetDateTime::now_utc(),
+                &schedule,
+                1,
+                &filter,
+                Duration::minutes(10),
+                worker,
+            )
+        };
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+
+        assert_eq!(claim(a).await?.len(), 1);
+        assert_eq!(claim(b).await?.len(), 1);
+
+        // only the leases of a are extended
+        assert_eq!(super::renew_leases(&pool, Duration::hours(1), a).await?, 1);
+
+        let renewed: Vec<Uuid> = sqlx::query_scalar(
+            "SELECT leased_by FROM menus WHERE lease_expires_at > NOW() + INTERVAL '30 minutes'",
+        )
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(renewed, [a]);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn write_day_detects_changes(pool: PgPool) -> anyhow::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let menu = Menu::from_supplier(Supplier::Skolmaten, "123", "School");
+        let date = date!(2023 - 02 - 13);
+
+        super::insert_menu(&mut conn, &menu).await?;
+
+        let writes: [(&[&str], bool); 4] = [
+            (&["Pannkakor", "Fisk"], true),
+            (&["Fisk", "Pannkakor"], false),
+            (&["Pannkakor"], true),
+            (&["Pannkakor", "Köttbullar"], true),
+        ];
+
+        for (meals, changed) in writes {
+            let day = Day::new(date, meals.iter().map(ToString::to_string).collect());
+            let written =
+                super::write_days(&mut conn, menu.id, vec![day], OffsetDateTime::now_utc()).await?;
+            assert_eq!(written.changed(), changed, "{meals:?}");
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn claim_expired_check_interval(pool: PgPool) -> anyhow::Result<()> {
+        let now = OffsetDateTime::now_utc();
+        let menus = [
+            ("often", Duration::hours(1), now - Duration::hours(2)),
+            ("rarely", Duration::days(7), now - Duration::days(2)),
+        ];
+
+        for (reference, interval, checked_at) in menus {
+            let menu = Menu {
+                checked_at: Some(checked_at),
+                ..Menu::from_supplier(Supplier::Skolmaten, reference, "School")
+            };
+            super::insert_menu(&pool, &menu).await?;
+
+            sqlx::query("UPDATE menus SET check_interval = $1 WHERE id = $2")
+                .bind(interval)
+                .bind(menu.id)
+                .execute(&pool)
+                .await?;
+        }
+
+        // both were checked more than a day ago
+        assert_eq!(expired(&pool, &[]).await?, ["often"]);
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn write_days(pool: PgPool) -> anyhow::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let menu = Menu::from_supplier(Supplier::Skolmaten, "123", "School");
+
+        super::insert_menu(&mut conn, &menu).await?;
+
+        let day =
+            |date, meals: &[&str]| Day::new(date, meals.iter().map(ToString::to_string).collect());
+
+        let written = super::write_days(
+            &mut conn,
+            menu.id,
+            vec![
+                day(date!(2023 - 02 - 13), &["Pannkakor", "Fisk"]),
+                day(date!(2023 - 02 - 14), &["Köttbullar"]),
+                day(date!(2023 - 02 - 15), &["Soppa"]),
+            ],
+            datetime!(2023-02-10 06:00 UTC),
+        )
+        .await?;
+        assert_eq!(written.meals, 4);
+        assert_eq!((written.inserted, written.deleted), (4, 0));
+
+        // a meal that is already in the history is still counted as deleted
+        sqlx::query(
+            r#"
+                INSERT INTO meal_history (menu_id, date, meal, first_seen, last_seen, removed_at)
+                    SELECT menu_id, date, meal, first_seen, last_seen, NOW() FROM meals
+                    WHERE meal = 'Fisk'
+            "#,
+        )
+        .execute(&mut conn)
+        .await?;
+
+        // days that are not included are left alone
+        let written = super::write_days(
+            &mut conn,
+            menu.id,
+            vec![
+                day(date!(2023 - 02 - 13), &["Pannkakor"]),
+                day(date!(2023 - 02 - 14), &[]),
+            ],
+            datetime!(2023-02-12 06:00 UTC),
+        )
+        .await?;
+        assert_eq!((written.inserted, written.deleted), (0, 2));
+
+        let stored: Vec<(Date, String)> =
+            sqlx::query_as("SELECT date, meal FROM meals ORDER BY date, meal")
+                .fetch_all(&mut conn)
+                .await?;
+        assert_eq!(
+            stored,
+            [
+                (date!(2023 - 02 - 13), "Pannkakor".to_owned()),
+                (date!(2023 - 02 - 15), "Soppa".to_owned()),
+            ]
+        );
+
+        let removed: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM meal_history")
+            .fetch_one(&mut conn)
+            .await?;
+        assert_eq!(removed, 2);
+
+        // the meals are seen and removed when they were fetched, and an older
+        // fetch does not move `last_seen` back
+        super::write_days(
+            &mut conn,
+            menu.id,
+            vec![day(date!(2023 - 02 - 13), &["Pannkakor"])],
+            datetime!(2023-02-11 06:00 UTC),
+        )
+        .await?;
+
+        let seen: (OffsetDateTime, OffsetDateTime) =
+            sqlx::query_as("SELECT first_seen, last_seen FROM meals WHERE meal = 'Pannkakor'")
+                .fetch_one(&mut conn)
+                .await?;
+        assert_eq!(
+            seen,
+            (
+                datetime!(2023-02-10 06:00 UTC),
+                datetime!(2023-02-12 06:00 UTC)
+            )
+        );
+
+        let removed_at: OffsetDateTime =
+            sqlx::query_scalar("SELECT removed_at FROM meal_history WHERE meal = 'Köttbullar'")
+                .fetch_one(&mut conn)
+                .await?;
+        assert_eq!(removed_at, datetime!(2023-02-12 06:00 UTC));
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn save_menu_is_atomic(pool: PgPool) -> anyhow::Result<()> {
+        let menu = Menu::from_supplier(Supplier::Skolmaten, "123", "School");
+        super::insert_menu(&pool, &menu).await?;
+
+        let schedule = super::Schedule {
+            max_age: Duration::days(1),
+            backoff: Duration::hours(1),
+            min_interval: Duration::hours(1),
+            max_interval: Duration::days(7),
+        };
+        let days = || {
+            vec![Day::new(
+                date!(2023 - 02 - 13),
+                vec!["Pannkakor".to_owned()],
+            )]
+        };
+
+        // make marking the menu as checked fail after the meals are written
+        sqlx::query("ALTER TABLE menus ADD CONSTRAINT unchecked CHECK (checked_at IS NULL)")
+            .execute(&pool)
+            .await?;
+        let e = super::save_menu(&pool, &menu, days(), &schedule)
+            .await
+            .unwrap_err();
+
+        let meals: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM meals")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(meals, 0);
+
+        sqlx::query("ALTER TABLE menus DROP CONSTRAINT unchecked")
+            .execute(&pool)
+            .await?;
+        let failure = crate::error::failure(&e);
+        super::update_menu(&pool, &menu, Some(&failure), false, &schedule).await?;
+
+        let (consecutive_failures, kind): (i32, Option<FailureKind>) =
+            sqlx::query_as("SELECT consecutive_failures, failure_kind FROM menus")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(consecutive_failures, 1);
+        assert_eq!(kind, Some(FailureKind::Transient));
+
+        super::save_menu(&pool, &menu, days(), &schedule).await?;
+
+        let (consecutive_failures, meals): (i32, i64) =
+            sqlx::query_as("SELECT consecutive_failures, (SELECT COUNT(*) FROM meals) FROM menus")
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!((consecutive_failures, meals), (0, 1));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+//! Keep indexing in the background instead of being invoked by cron, so that
+//! the geoindex and the HTTP clients are only set up once.
+
+use std::{net::SocketAddr, sync::Mutex, time::Duration};
+
+use sqlx::PgPool;
+use stor::{config::Config, run::IndexRun};
+use time::OffsetDateTime;
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use super::SearchTxn;
+
+#[derive(Debug, clap::Args)]
+#[group(id = "serve")]
+pub struct Args {
+    #[command(flatten)]
+    index: super::Args,
+
+    /// How often to look for expired menus.
+    #[arg(long, default_value = "60")]
+    refresh_interval_secs: u64,
+
+    /// How often to record the refreshes since the last time as an index
+    /// run, and check it for regressions.
+    #[arg(long, default_value = "3600")]
+    run_interval_secs: u64,
+
+    /// How often to download new menus, if `--load-menus` is set.
+    #[arg(long, default_value = "86400")]
+    load_menus_interval_secs: u64,
+
+    /// How often to sync the changed menus and meals to MeiliSearch, if
+    /// `--meili-url` is set.
+    #[arg(long, default_value = "900")]
+    meili_interval_secs: u64,
+
+    /// How often to remove old responses from the archive, if `--archive` is
+    /// set.
+    #[arg(long, default_value = "3600")]
+    archive_prune_interval_secs: u64,
+
+    /// Serve Prometheus metrics on `/metrics` at this address.
+    #[arg(long, env)]
+    metrics_addr: Option<SocketAddr>,
+}
+
+/// Cancels `token` on SIGTERM or Ctrl-C.
+async fn shutdown_signal(token: CancellationToken) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl-C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {},
+        () = terminate => {},
+    }
+
+    info!("received shutdown signal");
+    token.cancel();
+}
+
+/// Ticks every `secs` seconds, the first time `secs` seconds from now unless
+/// `now` is set.
+fn every(secs: u64, now: bool) -> Interval {
+    let period = Duration::from_secs(secs);
+    let start = if now {
+        Instant::now()
+    } else {
+        Instant::now() + period
+    };
+
+    let mut interval = interval_at(start, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval
+}
+
+/// Waits for the next tick of `interval`, returning `false` instead if
+/// `shutdown` is cancelled first.
+async fn tick(interval: &mut Interval, shutdown: &CancellationToken) -> bool {
+    tokio::select! {
+        biased;
+
+        () = shutdown.cancelled() => false,
+        _ = interval.tick() => true,
+    }
+}
+
+/// Run the refresh, menu loading, MeiliSearch sync and archive pruning on
+/// their own schedules until SIGTERM is received. The schedules run
+/// concurrently, so that a long refresh does not hold up the others. A
+/// refresh that is in progress stops picking up menus, but writes the ones
+/// that are being fetched. The refreshes are recorded together as one index
+/// run every `--run-interval-secs`, and when shutting down.
+pub async fn serve(opt: Args, config: &Config, pool: &PgPool) -> anyhow::Result<()> {
+    let shutdown = CancellationToken::new();
+    tokio::spawn(shutdown_signal(shutdown.clone()));
+
+    let Args {
+        index: mut opt,
+        refresh_interval_secs,
+        run_interval_secs,
+        load_menus_interval_secs,
+        meili_interval_secs,
+        archive_prune_interval_secs,
+        metrics_addr,
+    } = opt;
+
+    if let Some(addr) = metrics_addr {
+        let shutdown = shutdown.clone();
+
+        tokio::spawn(async move {
+            let shutdown = async move { shutdown.cancelled().await };
+
+            if let Err(e) = crate::metrics::serve(addr, shutdown).await {
+                error!("failed to serve metrics: {e:#}");
+            }
+        });
+    }
+
+    opt.configure(config);
+
+    let (client, archive) = opt.client(config)?;
+    let geoindex = super::build_geoindex(opt.osm_gh_pat.clone()).await?;
+    let search_txn = match geoindex.as_ref() {
+        Some(i) => Some(SearchTxn::new(i, opt.trast_url.clone()).await?),
+        None => None,
+    };
+
+    // the refresh borrows the geoindex, so the schedules are joined rather
+    // than spawned
+    let run = Mutex::new(IndexRun::new(OffsetDateTime::now_utc()));
+
+    let refreshes = async {
+        let mut interval = every(refresh_interval_secs, true);
+
+        while tick(&mut interval, &shutdown).await {
+            let res = super::refresh(
+                &opt,
+                pool,
+                &client,
+                search_txn.as_ref(),
+                shutdown.cancelled(),
+            );
+
+            match res.await {
+                Ok(r) => run.lock().unwrap().merge(&r),
+                Err(e) => error!("failed to refresh menus: {e:#}"),
+            }
+        }
+    };
+
+    let records = async {
+        let mut interval = every(run_interval_secs, false);
+
+        while tick(&mut interval, &shutdown).await {
+            let next = IndexRun::new(OffsetDateTime::now_utc());
+            let finished = std::mem::replace(&mut *run.lock().unwrap(), next);
+
+            if let Err(e) = super::record_run(&opt, pool, &finished).await {
+                error!("failed to record index run: {e:#}");
+            }
+        }
+    };
+
+    let load_menus = async {
+        if !opt.load_menus {
+            return;
+        }
+
+        let mut interval = every(load_menus_interval_secs, true);
+
+        while tick(&mut interval, &shutdown).await {
+            let res = async {
+                super::load_menus(&mut *pool.acquire().await?, &client, config, &opt.filter).await
+            };
+
+            if let Err(e) = res.await {
+                error!("failed to load menus: {e:#}");
+            }
+        }
+    };
+
+    let meili = async {
+        if !opt.meili.enabled() {
+            return;
+        }
+
+        let mut interval = every(meili_interval_secs, true);
+
+        while tick(&mut interval, &shutdown).await {
+            if let Err(e) = crate::meili::sync(&opt.meili, pool).await {
+                error!("failed to sync menus to meilisearch: {e:#}");
+            }
+        }
+    };
+
+    let prune = async {
+        let Some(archive) = &archive else {
+            return;
+        };
+
+        let mut interval = every(archive_prune_interval_secs, true);
+
+        while tick(&mut interval, &shutdown).await {
+            let archive = archive.clone();
+            let retention = opt.archive.retention();
+            let res = tokio::task::spawn_blocking(move || {
+                archive.prune(retention, OffsetDateTime::now_utc())
+            });
+
+            match res.await {
+                Ok(Ok(pruned)) => info!(pruned.entries, pruned.objects, "pruned archive"),
+                Ok(Err(e)) => error!("failed to prune archive: {e}"),
+                Err(e) => error!("failed to prune archive: {e}"),
+            }
+        }
+    };
+
+    info!("serving");
+
+    tokio::join!(refreshes, records, load_menus, meili, prune);
+
+    let run = run.into_inner().unwrap();
+
+    if let Err(e) = super::record_run(&opt, pool, &run).await {
+        error!("failed to record index run: {e:#}");
+    }
+
+    info!("shut down");
+
+    Ok(())
+}
@@ -1,19 +1,24 @@
 use std::ops::RangeInclusive;
 
-use futures::{stream, StreamExt};
-use reqwest::Client;
-use stor::{menu::Supplier, Menu};
-use strum::IntoEnumIterator;
+use futures::{future, stream, StreamExt};
+use stor::{config::Config, Menu};
 use supplier::ListDays;
 use thiserror::Error;
 use time::Date;
 use tracing::{debug, instrument};
 
-use crate::supplier::{kleins, matilda, mpi, sabis, skolmaten, sodexo};
+use crate::{client::Client, error::ScrapeError};
 
+pub mod archive;
+pub mod check;
+pub mod client;
+pub mod error;
 pub mod geosearch;
 pub mod index;
 mod mashie;
+pub mod meili;
+pub mod metrics;
+pub mod reparse;
 pub mod supplier;
 mod util;
 
@@ -23,18 +28,10 @@ pub type Result<T, E = anyhow::Error> = core::result::Result<T, E>;
 pub enum Error {
     #[error("menu not found")]
     MenuNotFound,
-    #[error("unexpected scrape error")]
-    ScrapeError,
-}
-
-impl Error {
-    fn scrape_error_with_context(_message: &str, _context: Option<&str>) -> Self {
-        Self::ScrapeError
-    }
-
-    fn scrape_error(message: &str) -> Self {
-        Self::scrape_error_with_context(message, None)
-    }
+    #[error("invalid supplier reference")]
+    InvalidReference,
+    #[error("supplier {0} is not registered")]
+    UnknownSupplier(stor::menu::Supplier),
 }
 
 pub const TZ: &time_tz::Tz = time_tz::timezones::db::europe::STOCKHOLM;
@@ -48,25 +45,28 @@ pub const USER_AGENT: &str = concat!(
     ")"
 );
 
-#[instrument]
-pub async fn list_menus(concurrent: usize) -> Result<Vec<Menu>> {
-    debug!("listing menus");
+/// The client to scrape with: rate limited according to the suppliers and
+/// `config`, and with a timeout.
+pub fn http_client(config: &Config) -> reqwest::Result<Client> {
+    Ok(supplier::client(
+        reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()?,
+        config,
+    ))
+}
 
-    let client = Client::new();
+/// List the menus of every supplier that can list them and is enabled in
+/// `config`.
+#[instrument(skip(client, config))]
+pub async fn list_menus(client: &Client, config: &Config, concurrent: usize) -> Result<Vec<Menu>> {
+    debug!("listing menus");
 
-    let menus = stream::iter(Supplier::iter())
+    let menus = stream::iter(supplier::SUPPLIERS)
+        .filter(|s| future::ready(s.capabilities().list_menus && config.supplier(s.id()).enabled))
         .map(|s| {
-            let client = client.clone();
-            async move {
-                match s {
-                    Supplier::Skolmaten => skolmaten::list_menus(&client).await,
-                    Supplier::Sodexo => sodexo::list_menus(&client).await,
-                    Supplier::Mpi => mpi::list_menus(&client).await,
-                    Supplier::Kleins => kleins::list_menus(&client).await,
-                    Supplier::Sabis => Ok(Vec::new()),
-                    Supplier::Matilda => matilda::list_menus(&client).await,
-                }
-            }
+            let client = client.scoped(s.id(), None);
+            async move { s.list_menus(&client).await }
         })
         .buffer_unordered(concurrent)
         .collect::<Vec<_>>()
@@ -83,20 +83,21 @@ pub async fn list_menus(concurrent: usize) -> Result<Vec<Menu>> {
 #[instrument(skip(client), fields(?supplier, %supplier_reference, ?dates))]
 pub async fn list_days(
     client: &Client,
-    supplier: Supplier,
+    supplier: stor::menu::Supplier,
     supplier_reference: &str,
     dates: RangeInclusive<Date>,
 ) -> Result<ListDays> {
-    match supplier {
-        Supplier::Skolmaten => {
-            skolmaten::list_days(client, supplier_reference.parse().unwrap(), dates).await
-        }
-        Supplier::Sodexo => sodexo::list_days(client, supplier_reference, dates).await,
-        Supplier::Mpi => mpi::list_days(client, supplier_reference, dates).await,
-        Supplier::Kleins => kleins::list_days(client, supplier_reference, dates).await,
-        Supplier::Sabis => sabis::list_days(client, supplier_reference).await,
-        Supplier::Matilda => {
-            matilda::list_days(client, &supplier_reference.parse().unwrap(), dates).await
-        }
-    }
+    let client = client
+        .scoped(supplier, Some(supplier_reference))
+        .for_dates(dates.clone());
+
+    crate::supplier::get(supplier)?
+        .list_days(&client, supplier_reference, dates)
+        .await
+        .map_err(|mut e| {
+            if let Some(e) = e.downcast_mut::<ScrapeError>() {
+                e.supplier.get_or_insert(supplier);
+            }
+            e
+        })
 }
@@ -1,9 +1,10 @@
-use std::env;
+use std::path::PathBuf;
 
+use anyhow::{bail, Context};
 use clap::Parser;
 use clap::Subcommand;
 use dotenv::dotenv;
-use munin::index;
+use munin::{archive, check, index, meili, metrics, reparse};
 use opentelemetry::sdk::propagation::TraceContextPropagator;
 use opentelemetry::sdk::trace;
 use opentelemetry::sdk::trace::Sampler;
@@ -11,6 +12,9 @@ use opentelemetry::sdk::Resource;
 use opentelemetry::KeyValue;
 use opentelemetry_otlp::WithExportConfig;
 use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use stor::config::{Config, Required};
+use tracing::error;
 use tracing::metadata::LevelFilter;
 use tracing_subscriber::fmt;
 use tracing_subscriber::prelude::*;
@@ -20,20 +24,56 @@ use index::index;
 
 #[derive(Debug, Parser)]
 struct Opt {
+    /// TOML configuration file shared with hugin. Settings given on the
+    /// command line or in the environment take precedence.
+    #[arg(long, env = stor::config::CONFIG_ENV)]
+    config: Option<PathBuf>,
+
     #[arg(long, env)]
-    database_url: String,
+    database_url: Option<String>,
 
     #[command(subcommand)]
     cmd: Command,
 
-    #[arg(long, env, default_value = "http://localhost:4317")]
-    otlp_endpoint: String,
+    #[arg(long, env)]
+    otlp_endpoint: Option<String>,
+
+    /// Push Prometheus metrics to this Pushgateway when the command is done.
+    /// `serve` exposes them on `--metrics-addr` instead.
+    #[arg(long, env)]
+    pushgateway_url: Option<String>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
-    /// Fetch new menus and days
+    /// Fetch new menus and days, or print them with --dry-run
     Index(index::Args),
+
+    /// Keep indexing on a schedule until SIGTERM is received
+    Serve(index::serve::Args),
+
+    /// Push the menus and meals that changed since the last sync to MeiliSearch
+    MeiliSync(meili::Args),
+
+    /// Check that the scrapers still understand the pages of the suppliers
+    Check(check::Args),
+
+    /// Reparse archived responses and fix the stored days
+    Reparse(reparse::Args),
+
+    /// Manage the response archive
+    #[command(subcommand)]
+    Archive(archive::Command),
+
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Validate the configuration and print it without secrets
+    Check,
 }
 
 #[tokio::main]
@@ -41,41 +81,94 @@ async fn main() -> anyhow::Result<()> {
     dotenv().ok();
     let opt = Opt::parse();
 
-    init_telemetry(opt.otlp_endpoint)?;
+    let mut config = Config::load(opt.config.as_deref())?;
 
-    let pool = PgPoolOptions::new().connect(&opt.database_url).await?;
+    if let Some(url) = opt.database_url {
+        config.database.url = Some(url);
+    }
+    if let Some(endpoint) = opt.otlp_endpoint {
+        config.telemetry.otlp_endpoint = endpoint;
+    }
 
-    stor::db::MIGRATOR.run(&pool).await?;
+    if let Command::Config(ConfigCommand::Check) = opt.cmd {
+        return config.check(&[Required::Database]);
+    }
+
+    let problems = config.problems(&[]);
+    if !problems.is_empty() {
+        bail!("invalid configuration: {}", problems.join("; "));
+    }
+
+    init_telemetry(&config.telemetry)?;
+
+    let res = run(opt.cmd, &config).await;
 
-    match opt.cmd {
-        Command::Index(opt) => {
-            index(opt, &pool).await?;
+    if let Some(ref url) = opt.pushgateway_url {
+        if let Err(e) = metrics::push(url, env!("CARGO_PKG_NAME")).await {
+            error!("failed to push metrics: {e}");
         }
     }
 
-    pool.close().await;
+    res
+}
+
+async fn run(cmd: Command, config: &Config) -> anyhow::Result<()> {
+    match cmd {
+        Command::Index(args) if args.dry_run => index::dry_run::run(args, config).await?,
+        Command::Index(args) => {
+            let pool = connect(config).await?;
+            index(args, config, &pool).await?;
+            pool.close().await;
+        }
+        Command::Serve(args) => {
+            let pool = connect(config).await?;
+            index::serve::serve(args, config, &pool).await?;
+            pool.close().await;
+        }
+        Command::MeiliSync(args) => {
+            let pool = connect(config).await?;
+            meili::run(args, config, &pool).await?;
+            pool.close().await;
+        }
+        Command::Reparse(args) => {
+            let pool = connect(config).await?;
+            reparse::reparse(args, &pool).await?;
+            pool.close().await;
+        }
+        Command::Check(args) => check::check(args, config).await?,
+        Command::Archive(cmd) => archive::run(cmd)?,
+        Command::Config(ConfigCommand::Check) => unreachable!("handled before telemetry"),
+    }
 
     Ok(())
 }
 
-fn init_telemetry(otlp_endpoint: impl Into<String>) -> anyhow::Result<()> {
-    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+async fn connect(config: &Config) -> anyhow::Result<PgPool> {
+    let database_url = config
+        .database
+        .url
+        .as_deref()
+        .context("DATABASE_URL is required")?;
+    let pool = PgPoolOptions::new().connect(database_url).await?;
 
-    let sample_rate = env::var("TRACE_SAMPLE_RATE")
-        .ok()
-        .and_then(|v| v.parse().ok())
-        .unwrap_or(0.01);
+    stor::db::MIGRATOR.run(&pool).await?;
+
+    Ok(pool)
+}
+
+fn init_telemetry(config: &stor::config::Telemetry) -> anyhow::Result<()> {
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
 
     let exporter = opentelemetry_otlp::new_exporter()
         .tonic()
-        .with_endpoint(otlp_endpoint);
+        .with_endpoint(&config.otlp_endpoint);
 
     let tracer = opentelemetry_otlp::new_pipeline()
         .tracing()
         .with_exporter(exporter)
         .with_trace_config(
             trace::config()
-                .with_sampler(Sampler::TraceIdRatioBased(sample_rate))
+                .with_sampler(Sampler::TraceIdRatioBased(config.trace_sample_rate))
                 .with_resource(Resource::new(vec![
                     KeyValue::new(
                         opentelemetry_semantic_conventions::resource::SERVICE_NAME,
@@ -93,7 +186,8 @@ fn init_telemetry(otlp_endpoint: impl Into<String>) -> anyhow::Result<()> {
 
     tracing_subscriber::registry()
         .with(
-            fmt::layer().with_filter(
+            // stdout is for the output of commands such as `index --dry-run`
+            fmt::layer().with_writer(std::io::stderr).with_filter(
                 EnvFilter::builder()
                     .with_default_directive(LevelFilter::INFO.into())
                     .from_env_lossy(),
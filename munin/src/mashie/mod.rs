@@ -1,17 +1,27 @@
 mod scrape;
 
-use std::ops::RangeInclusive;
+use std::{
+    collections::BTreeMap,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 pub use scrape::*;
 
-use reqwest::{header::CONTENT_LENGTH, Client};
+use reqwest::header::CONTENT_LENGTH;
 use select::{document::Document, predicate::Name};
 use serde::Deserialize;
 use stor::menu::{Patch, Supplier};
 use time::Date;
-use tracing::instrument;
+use tokio::time::Instant;
+use tracing::{debug, instrument};
 
-#[derive(Deserialize, Debug)]
+/// How long a downloaded menu directory is reused. Long enough to cover an
+/// index run, short enough to pick up new menus in the next one.
+const DIRECTORY_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Menu {
     id: String,
     title: String,
@@ -41,12 +51,69 @@ pub async fn list_menus(client: &Client, host: &str) -> Result<Vec<Menu>> {
     Ok(menus)
 }
 
+#[derive(Debug)]
+struct Listing {
+    fetched_at: Instant,
+    menus: Arc<Vec<Menu>>,
+}
+
+/// The menus of every Mashie host, downloaded at most once per
+/// [`DIRECTORY_TTL`]. Without it, every [`list_days`] would download the
+/// entire directory just to look up a single menu.
+#[derive(Debug)]
+struct Directory {
+    hosts: Mutex<BTreeMap<String, Arc<tokio::sync::Mutex<Option<Listing>>>>>,
+}
+
+static DIRECTORY: Directory = Directory::new();
+
+impl Directory {
+    const fn new() -> Self {
+        Self {
+            hosts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    async fn get(&self, client: &Client, host: &str) -> Result<Arc<Vec<Menu>>> {
+        // snapshots are of different times, and may not agree on the menus
+        if client.is_snapshot() {
+            return Ok(Arc::new(list_menus(client, host).await?));
+        }
+
+        let entry = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host.to_owned())
+            .or_default()
+            .clone();
+
+        // concurrent lookups wait for the first one instead of all downloading
+        // the directory at once
+        let mut listing = entry.lock().await;
+
+        match listing.as_ref() {
+            Some(l) if l.fetched_at.elapsed() < DIRECTORY_TTL => Ok(l.menus.clone()),
+            _ => {
+                debug!(host, "downloading menu directory");
+                let menus = Arc::new(list_menus(client, host).await?);
+                *listing = Some(Listing {
+                    fetched_at: Instant::now(),
+                    menus: menus.clone(),
+                });
+                Ok(menus)
+            }
+        }
+    }
+}
+
 #[instrument(level = "debug", skip(client))]
 pub async fn query_menu(client: &Client, host: &str, menu_slug: &str) -> Result<Menu> {
-    let menus = list_menus(client, host).await?;
+    let menus = DIRECTORY.get(client, host).await?;
     let menu = menus
-        .into_iter()
+        .iter()
         .find(|m| m.id == menu_slug)
+        .cloned()
         .ok_or(Error::MenuNotFound)?;
 
     Ok(menu)
@@ -61,7 +128,7 @@ pub async fn list_days(
 ) -> Result<ListDays> {
     let menu = query_menu(client, host, reference).await?;
     let url = format!("{}/{}", host, menu.path);
-    let html = reqwest::get(&url).await?.text().await?;
+    let html = client.get(&url).send().await?.text().await?;
     let doc = Document::from(html.as_str());
     let days = scrape_days(&doc)
         .filter(|day| dates.contains(&day.date))
@@ -81,16 +148,49 @@ pub async fn list_days(
 
 /// Automagically generate a Mashie client.
 macro_rules! mashie_impl {
-    ($host:literal, $supplier:expr) => {
+    ($name:ident, $host:literal, $supplier:expr, $canary:literal) => {
         use std::ops::RangeInclusive;
 
-        use reqwest::Client;
+        use async_trait::async_trait;
         use stor::Menu;
         use time::Date;
-        use $crate::{mashie, supplier::ListDays, Result};
+        use $crate::{client::Client, mashie, supplier::ListDays, Result};
 
         const HOST: &str = $host;
 
+        pub struct $name;
+
+        #[async_trait]
+        impl $crate::supplier::Supplier for $name {
+            fn id(&self) -> stor::menu::Supplier {
+                $supplier
+            }
+
+            fn hosts(&self) -> &'static [&'static str] {
+                &[HOST]
+            }
+
+            fn canary(&self) -> $crate::supplier::Canary {
+                $crate::supplier::Canary {
+                    reference: $canary,
+                    classes: mashie::CLASSES,
+                }
+            }
+
+            async fn list_menus(&self, client: &Client) -> Result<Vec<Menu>> {
+                list_menus(client).await
+            }
+
+            async fn list_days(
+                &self,
+                client: &Client,
+                reference: &str,
+                dates: RangeInclusive<Date>,
+            ) -> Result<ListDays> {
+                list_days(client, reference, dates).await
+            }
+        }
+
         pub async fn list_menus(client: &Client) -> Result<Vec<Menu>> {
             let menus = mashie::list_menus(client, HOST)
                 .await?
@@ -111,11 +211,10 @@ macro_rules! mashie_impl {
 
         #[cfg(test)]
         mod auto_tests {
-            use reqwest::Client;
-
             #[tokio::test]
             async fn nonempty() {
-                let menus = super::list_menus(&Client::new()).await.unwrap();
+                let client = $crate::client::fixture(&stringify!($name).to_lowercase());
+                let menus = super::list_menus(&client).await.unwrap();
                 assert!(!menus.is_empty());
             }
         }
@@ -124,25 +223,30 @@ macro_rules! mashie_impl {
 
 pub(crate) use mashie_impl;
 
-use crate::{supplier::ListDays, Error, Result};
+use crate::{client::Client, supplier::ListDays, Error, Result};
 
 #[cfg(test)]
 mod tests {
-    use reqwest::Client;
+    use std::sync::Arc;
+
+    use crate::client::fixture;
+
+    use super::Directory;
 
     #[tokio::test]
     async fn list_menus() {
-        let menus = super::list_menus(&Client::new(), "https://sodexo.mashie.com")
+        let menus = super::list_menus(&fixture("sodexo"), "https://sodexo.mashie.com")
             .await
             .unwrap();
 
-        assert!(menus.len() > 100);
+        assert_eq!(menus.len(), 3);
     }
 
     #[tokio::test]
     async fn query_menu() {
+        let client = fixture("sodexo");
         let menu = super::query_menu(
-            &Client::new(),
+            &client,
             "https://sodexo.mashie.com",
             "e8851c61-013b-4617-93d9-adab00820bcd",
         )
@@ -153,9 +257,21 @@ mod tests {
         assert_eq!(menu.id, "e8851c61-013b-4617-93d9-adab00820bcd");
 
         assert!(
-            super::query_menu(&Client::new(), "https://sodexo.mashie.com", "invalid")
+            super::query_menu(&client, "https://sodexo.mashie.com", "invalid")
                 .await
                 .is_err()
         );
     }
+
+    #[tokio::test]
+    async fn directory() {
+        let directory = Directory::new();
+        let host = "https://sodexo.mashie.com";
+
+        let a = directory.get(&fixture("sodexo"), host).await.unwrap();
+        // the sabis cassette has no mashie responses, so this must be cached
+        let b = directory.get(&fixture("sabis"), host).await.unwrap();
+
+        assert!(Arc::ptr_eq(&a, &b));
+    }
 }
@@ -65,6 +65,14 @@ fn parse_day_node(node: &Node) -> Option<Day> {
     Some(Day::new(date, meals))
 }
 
+/// The classes [`scrape_days`] looks for.
+pub const CLASSES: &[&str] = &[
+    "panel-group",
+    "panel-heading",
+    "pull-right",
+    "app-daymenu-name",
+];
+
 #[allow(clippy::module_name_repetitions)]
 pub fn scrape_days(doc: &Document) -> impl Iterator<Item = Day> + '_ {
     let day_elems = doc.find(Class("panel-group").child(Class("panel")));
@@ -73,10 +81,9 @@ pub fn scrape_days(doc: &Document) -> impl Iterator<Item = Day> + '_ {
 
 #[cfg(test)]
 mod tests {
-    use reqwest::Client;
     use time::macros::date;
 
-    use crate::mashie::query_menu;
+    use crate::{client::fixture, mashie::query_menu};
 
     use super::*;
 
@@ -106,17 +113,18 @@ mod tests {
     #[tokio::test]
     async fn scrape_days_test() {
         let host = "https://sodexo.mashie.com";
-        let menu = query_menu(&Client::new(), host, "4854efa1-29b3-4534-8820-abeb008ed759")
+        let client = fixture("sodexo");
+        let menu = query_menu(&client, host, "4854efa1-29b3-4534-8820-abeb008ed759")
             .await
             .unwrap();
         assert_eq!(menu.title, "Karolina, Pysslingen");
 
         let url = format!("{}/{}", host, menu.path);
-        let html = reqwest::get(&url).await.unwrap().text().await.unwrap();
+        let html = client.get(&url).send().await.unwrap().text().await.unwrap();
         let doc = Document::from(html.as_str());
         let days = scrape_days(&doc).collect::<Vec<_>>();
 
-        assert!(!days.is_empty());
+        assert_eq!(days.len(), 5);
 
         assert_eq!(scrape_days(&Document::from("<h1>no days</h1>")).count(), 0);
     }
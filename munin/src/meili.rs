@@ -0,0 +1,673 @@
+//! Keep the MeiliSearch indexes in sync with the database: `menus`, and
+//! `meals` with the upcoming meals of every menu. Only the menus that were
+//! created or checked since the last sync, and their meals, are uploaded,
+//! since every change to a menu or its meals also marks it as checked.
+
+use std::{collections::HashSet, ops::RangeInclusive, time::Duration};
+
+use anyhow::{bail, Context};
+use meilisearch_sdk::{
+    documents::DocumentsQuery, indexes::Index, task_info::TaskInfo, tasks::Task, Client,
+};
+use osm::OsmId;
+use serde::{Deserialize, Serialize, Serializer};
+use sqlx::{FromRow, PgPool};
+use stor::{config::Config, menu::Supplier};
+use time::{Date, OffsetDateTime};
+use time_tz::OffsetDateTimeExt;
+use tracing::{debug, info};
+use uuid::Uuid;
+
+const MENUS: &str = "menus";
+const MEALS: &str = "meals";
+
+/// How many document ids to fetch at once when looking for removed menus.
+const ID_PAGE: usize = 10_000;
+
+/// How long before the start of the last sync to look for changes. The
+/// indexers set `checked_at` by their own clocks, before they commit, so a
+/// menu can become visible with a `checked_at` from before the sync started.
+const SYNC_OVERLAP: time::Duration = time::Duration::minutes(10);
+
+#[derive(Debug, clap::Args)]
+#[group(id = "meili")]
+pub struct Args {
+    /// If provided, the menus will be inserted into the given
+    /// MeiliSearch instance.
+    #[arg(long, env)]
+    meili_url: Option<String>,
+
+    #[arg(long, env, hide_env_values = true, default_value = "")]
+    meili_key: String,
+
+    /// Upload every menu, not only the ones that changed since the last
+    /// sync, e.g. after the index was wiped.
+    #[arg(long)]
+    meili_full: bool,
+
+    /// How many documents to upload at once.
+    #[arg(long, default_value = "1000")]
+    meili_chunk_size: usize,
+
+    /// How long to wait for MeiliSearch to index a chunk.
+    #[arg(long, default_value = "300")]
+    meili_timeout_secs: u64,
+
+    /// How many days after today to include in the `meals` index.
+    #[arg(long, default_value = "14")]
+    meili_meal_days: u32,
+}
+
+impl Args {
+    /// Fill in what was not given on the command line from `config`.
+    pub(crate) fn configure(&mut self, config: &Config) {
+        if self.meili_url.is_none() {
+            self.meili_url = config.meili.url.clone();
+        }
+
+        if self.meili_key.is_empty() {
+            self.meili_key = config.meili.key.clone().unwrap_or_default();
+        }
+    }
+
+    /// Whether there is a MeiliSearch instance to sync to.
+    pub(crate) fn enabled(&self) -> bool {
+        self.meili_url.is_some()
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.meili_timeout_secs)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Geo {
+    lng: f64,
+    lat: f64,
+}
+
+#[derive(Debug, FromRow)]
+struct Menu {
+    #[sqlx(flatten)]
+    inner: stor::Menu,
+    last_day: Option<Date>,
+}
+
+impl Serialize for Menu {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let Self { inner, last_day } = self;
+        let stor::Menu {
+            id,
+            title,
+            supplier,
+            supplier_reference: _,
+            location,
+            osm_id,
+            created_at,
+            checked_at,
+            consecutive_failures,
+            last_failure: _,
+        } = inner;
+
+        #[derive(Debug, Serialize)]
+        struct Doc<'a> {
+            id: Uuid,
+            title: &'a str,
+            #[serde(rename = "_geo", skip_serializing_if = "Option::is_none")]
+            geo: Option<Geo>,
+            last_day: Option<Date>,
+            supplier: Supplier,
+            osm_id: Option<OsmId>,
+            #[serde(with = "time::serde::rfc3339::option")]
+            created_at: Option<OffsetDateTime>,
+            #[serde(with = "time::serde::rfc3339::option")]
+            checked_at: Option<OffsetDateTime>,
+            consecutive_failures: i32,
+        }
+
+        Doc {
+            id: *id,
+            title,
+            geo: location.map(|p| Geo {
+                lng: p.x(),
+                lat: p.y(),
+            }),
+            last_day: *last_day,
+            supplier: *supplier,
+            osm_id: *osm_id,
+            created_at: *created_at,
+            checked_at: *checked_at,
+            consecutive_failures: *consecutive_failures,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A meal in the `meals` index.
+#[derive(Debug, FromRow)]
+struct Meal {
+    menu_id: Uuid,
+    date: Date,
+    meal: String,
+    title: String,
+    longitude: Option<f64>,
+    latitude: Option<f64>,
+}
+
+/// The id of the document of a meal.
+fn meal_id(menu_id: Uuid, date: Date, meal: &str) -> Uuid {
+    Uuid::new_v5(&menu_id, format!("{date}/{meal}").as_bytes())
+}
+
+impl Serialize for Meal {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let Self {
+            menu_id,
+            date,
+            meal,
+            title,
+            longitude,
+            latitude,
+        } = self;
+
+        #[derive(Debug, Serialize)]
+        struct Doc<'a> {
+            id: Uuid,
+            menu_id: Uuid,
+            /// The title of the menu.
+            title: &'a str,
+            date: Date,
+            /// The start of `date` (UTC) as a Unix timestamp, since only
+            /// numbers can be filtered by range.
+            date_timestamp: i64,
+            meal: &'a str,
+            #[serde(rename = "_geo", skip_serializing_if = "Option::is_none")]
+            geo: Option<Geo>,
+        }
+
+        Doc {
+            id: meal_id(*menu_id, *date, meal),
+            menu_id: *menu_id,
+            title,
+            date: *date,
+            date_timestamp: date.midnight().assume_utc().unix_timestamp(),
+            meal,
+            geo: longitude.zip(*latitude).map(|(lng, lat)| Geo { lng, lat }),
+        }
+        .serialize(serializer)
+    }
+}
+
+/// What a sync of an index did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Synced {
+    pub uploaded: usize,
+    pub deleted: usize,
+}
+
+/// Sync the `menus` and `meals` indexes.
+pub async fn sync(opt: &Args, pool: &PgPool) -> anyhow::Result<()> {
+    let url = opt.meili_url.as_deref().context("MEILI_URL is required")?;
+    let client = Client::new(url, &opt.meili_key);
+
+    for (index, res) in [
+        (MENUS, sync_menus(opt, &client, pool).await),
+        (MEALS, sync_meals(opt, &client, pool).await),
+    ] {
+        let Synced { uploaded, deleted } =
+            res.with_context(|| format!("failed to sync {index}"))?;
+        info!(index, uploaded, deleted, "synced to meilisearch");
+    }
+
+    Ok(())
+}
+
+/// `munin meili-sync`
+pub async fn run(mut opt: Args, config: &Config, pool: &PgPool) -> anyhow::Result<()> {
+    opt.configure(config);
+    sync(&opt, pool).await
+}
+
+/// The time of the last sync of `uid`, unless everything is to be uploaded.
+async fn since(
+    opt: &Args,
+    pool: &PgPool,
+    uid: &str,
+    created: bool,
+) -> sqlx::Result<Option<OffsetDateTime>> {
+    if opt.meili_full || created {
+        Ok(None)
+    } else {
+        last_sync(pool, uid).await
+    }
+}
+
+/// Upload the menus that changed since the last sync, and delete the
+/// documents of menus that no longer exist.
+async fn sync_menus(opt: &Args, client: &Client, pool: &PgPool) -> anyhow::Result<Synced> {
+    let started_at = db_now(pool).await?;
+
+    let (index, created) = get_or_create_index(client, MENUS).await?;
+    index
+        .set_sortable_attributes(&["checked_at", "last_day", "_geo"])
+        .await?;
+    index
+        .set_filterable_attributes(&["slug", "checked_at", "last_day", "_geo"])
+        .await?;
+
+    let since = since(opt, pool, MENUS, created).await?;
+    let menus = changed_menus(pool, since).await?;
+
+    info!(
+        menus = menus.len(),
+        ?since,
+        "uploading menus to meilisearch"
+    );
+
+    upload(&index, &menus, opt.meili_chunk_size, opt.timeout()).await?;
+
+    let mut ids = document_ids(&index).await?;
+    // listed after the documents, so that menus added in between are kept
+    let existing: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM menus")
+        .fetch_all(pool)
+        .await?;
+    for id in existing {
+        ids.remove(&id);
+    }
+    let deleted = delete(&index, ids, opt.timeout()).await?;
+
+    record_sync(pool, MENUS, started_at).await?;
+
+    Ok(Synced {
+        uploaded: menus.len(),
+        deleted,
+    })
+}
+
+/// Upload the upcoming meals of the menus that changed since the last sync,
+/// and of the days that have come within `--meili-meal-days` since then.
+/// Meals that are no longer upcoming, or no longer on their menu, are
+/// deleted.
+async fn sync_meals(opt: &Args, client: &Client, pool: &PgPool) -> anyhow::Result<Synced> {
+    let started_at = db_now(pool).await?;
+    let today = started_at.to_timezone(crate::TZ).date();
+    let dates = today..=today + time::Duration::days(opt.meili_meal_days.into());
+
+    let (index, created) = get_or_create_index(client, MEALS).await?;
+    index.set_searchable_attributes(&["meal", "title"]).await?;
+    index
+        .set_sortable_attributes(&["date_timestamp", "_geo"])
+        .await?;
+    index
+        .set_filterable_attributes(&["menu_id", "date", "date_timestamp", "_geo"])
+        .await?;
+
+    let since = since(opt, pool, MEALS, created).await?;
+    let meals = changed_meals(pool, dates.clone(), since, opt.meili_meal_days).await?;
+
+    info!(
+        meals = meals.len(),
+        ?since,
+        "uploading meals to meilisearch"
+    );
+
+    upload(&index, &meals, opt.meili_chunk_size, opt.timeout()).await?;
+
+    let mut ids = document_ids(&index).await?;
+    let existing = sqlx::query_as::<_, (Uuid, Date, String)>(
+        "SELECT menu_id, date, meal FROM meals WHERE date BETWEEN $1 AND $2",
+    )
+    .bind(dates.start())
+    .bind(dates.end())
+    .fetch_all(pool)
+    .await?;
+    for (menu_id, date, meal) in existing {
+        ids.remove(&meal_id(menu_id, date, &meal));
+    }
+    let deleted = delete(&index, ids, opt.timeout()).await?;
+
+    record_sync(pool, MEALS, started_at).await?;
+
+    Ok(Synced {
+        uploaded: meals.len(),
+        deleted,
+    })
+}
+
+/// The menus created or checked since `since`, or all of them.
+async fn changed_menus(pool: &PgPool, since: Option<OffsetDateTime>) -> sqlx::Result<Vec<Menu>> {
+    sqlx::query_as::<_, Menu>(
+        r#"
+            SELECT m.*, MAX(d.date) AS last_day FROM menus AS m
+            LEFT JOIN meals AS d ON d.menu_id = m.id
+            WHERE $1::timestamptz IS NULL OR m.created_at >= $1 OR m.checked_at >= $1
+            GROUP BY m.id
+        "#,
+    )
+    .bind(since)
+    .fetch_all(pool)
+    .await
+}
+
+/// The meals within `dates` of the menus created or checked since `since`,
+/// or all of them. Meals on the days that were more than `days` days ahead
+/// at `since` are included as well, since they were not synced then.
+async fn changed_meals(
+    pool: &PgPool,
+    dates: RangeInclusive<Date>,
+    since: Option<OffsetDateTime>,
+    days: u32,
+) -> sqlx::Result<Vec<Meal>> {
+    let horizon =
+        since.map(|t| t.to_timezone(crate::TZ).date() + time::Duration::days(days.into()));
+
+    sqlx::query_as::<_, Meal>(
+        r#"
+            SELECT d.menu_id, d.date, d.meal, m.title, m.longitude, m.latitude
+            FROM meals AS d
+            JOIN menus AS m ON m.id = d.menu_id
+            WHERE d.date BETWEEN $1 AND $2 AND (
+                $3::timestamptz IS NULL OR
+                m.created_at >= $3 OR
+                m.checked_at >= $3 OR
+                d.date > $4
+            )
+        "#,
+    )
+    .bind(dates.start())
+    .bind(dates.end())
+    .bind(since)
+    .bind(horizon)
+    .fetch_all(pool)
+    .await
+}
+
+/// The time according to the database, which the syncs are recorded by.
+async fn db_now(pool: &PgPool) -> sqlx::Result<OffsetDateTime> {
+    sqlx::query_scalar("SELECT NOW()").fetch_one(pool).await
+}
+
+/// When the last successful sync of `index_uid` started, less
+/// [`SYNC_OVERLAP`].
+async fn last_sync(pool: &PgPool, index_uid: &str) -> sqlx::Result<Option<OffsetDateTime>> {
+    let started_at: Option<OffsetDateTime> =
+        sqlx::query_scalar("SELECT started_at FROM meili_syncs WHERE index_uid = $1")
+            .bind(index_uid)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(started_at.map(|t| t - SYNC_OVERLAP))
+}
+
+async fn record_sync(
+    pool: &PgPool,
+    index_uid: &str,
+    started_at: OffsetDateTime,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"
+            INSERT INTO meili_syncs (index_uid, started_at) VALUES ($1, $2)
+            ON CONFLICT (index_uid) DO UPDATE SET started_at = excluded.started_at
+        "#,
+    )
+    .bind(index_uid)
+    .bind(started_at)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Add `documents` in chunks of `chunk_size`, waiting for each chunk to be
+/// indexed before sending the next.
+async fn upload<T>(
+    index: &Index,
+    documents: &[T],
+    chunk_size: usize,
+    timeout: Duration,
+) -> anyhow::Result<()>
+where
+    T: Serialize,
+{
+    let pb = indicatif::ProgressBar::new(documents.len() as u64)
+        .with_style(indicatif::ProgressStyle::with_template("{msg} {bar} {pos}/{len}").unwrap())
+        .with_message(format!("uploading to {}", index.uid));
+
+    for chunk in documents.chunks(chunk_size.max(1)) {
+        let task = index.add_documents(chunk, Some("id")).await?;
+        debug!("queued {} documents for meilisearch indexing", chunk.len());
+
+        wait(&index.client, task, timeout).await?;
+        pb.inc(chunk.len() as u64);
+    }
+
+    pb.finish_and_clear();
+
+    Ok(())
+}
+
+/// The ids of all documents in `index`.
+async fn document_ids(index: &Index) -> anyhow::Result<HashSet<Uuid>> {
+    #[derive(Debug, Deserialize)]
+    struct Doc {
+        id: Uuid,
+    }
+
+    let mut ids = HashSet::new();
+    let mut query = DocumentsQuery::new(index);
+    query.with_fields(["id"]).with_limit(ID_PAGE);
+
+    loop {
+        let page = index
+            .get_documents_with::<Doc>(query.with_offset(ids.len()))
+            .await?;
+        let n = page.results.len();
+        ids.extend(page.results.into_iter().map(|d| d.id));
+
+        if n < ID_PAGE {
+            break;
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Delete the documents with `ids` from `index`.
+async fn delete(index: &Index, ids: HashSet<Uuid>, timeout: Duration) -> anyhow::Result<usize> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let ids = ids.into_iter().collect::<Vec<_>>();
+    let task = index.delete_documents(&ids).await?;
+    wait(&index.client, task, timeout).await?;
+
+    Ok(ids.len())
+}
+
+/// Wait for `task` to be processed, failing if it fails or takes longer than
+/// `timeout`.
+async fn wait(client: &Client, task: TaskInfo, timeout: Duration) -> anyhow::Result<()> {
+    match task
+        .wait_for_completion(client, None, Some(timeout))
+        .await?
+    {
+        Task::Succeeded { content } => {
+            debug!(
+                "meilisearch task took {:.02} seconds",
+                content.duration.as_secs_f64()
+            );
+
+            Ok(())
+        }
+        Task::Failed { content } => bail!(meilisearch_sdk::errors::Error::from(content.error)),
+        Task::Enqueued { .. } | Task::Processing { .. } => {
+            bail!("timeout waiting for meilisearch after {timeout:?}")
+        }
+    }
+}
+
+/// Get the index `uid`, creating it if it does not exist. Also returns
+/// whether it was created.
+async fn get_or_create_index(
+    client: &Client,
+    uid: impl AsRef<str>,
+) -> anyhow::Result<(Index, bool)> {
+    let uid = uid.as_ref();
+
+    if let Ok(index) = client.get_index(uid).await {
+        Ok((index, false))
+    } else {
+        let task = client.create_index(uid, None).await?;
+        let task = task
+            .wait_for_completion(client, None, Some(std::time::Duration::from_secs(10)))
+            .await?;
+        match task {
+            Task::Enqueued { .. } | Task::Processing { .. } => {
+                bail!("timeout waiting for index creation")
+            }
+            Task::Failed { content } => {
+                bail!(meilisearch_sdk::errors::Error::from(content.error))
+            }
+            Task::Succeeded { .. } => Ok((task.try_make_index(client).unwrap(), true)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::Point;
+    use stor::Menu;
+    use time::Duration;
+
+    use crate::index::insert_menu;
+
+    use super::*;
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn changed_menus(pool: PgPool) -> anyhow::Result<()> {
+        let now = OffsetDateTime::now_utc();
+        let synced_at = db_now(&pool).await?;
+        let menus = [
+            ("old", now - Duration::days(2)),
+            // checked before the sync started, but committed after it
+            ("late", synced_at - Duration::minutes(1)),
+            ("new", now),
+        ];
+
+        for (reference, checked_at) in menus {
+            let menu = Menu {
+                created_at: Some(checked_at),
+                checked_at: Some(checked_at),
+                ..Menu::from_supplier(Supplier::Skolmaten, reference, "School")
+            };
+            insert_menu(&pool, &menu).await?;
+        }
+
+        let references = |menus: Vec<super::Menu>| {
+            let mut references = menus
+                .into_iter()
+                .map(|m| m.inner.supplier_reference)
+                .collect::<Vec<_>>();
+            references.sort();
+            references
+        };
+
+        assert_eq!(last_sync(&pool, MENUS).await?, None);
+        assert_eq!(
+            references(super::changed_menus(&pool, None).await?),
+            ["late", "new", "old"]
+        );
+
+        record_sync(&pool, MENUS, now - Duration::days(1)).await?;
+        let since = last_sync(&pool, MENUS).await?;
+        assert!(since.is_some());
+        assert_eq!(
+            references(super::changed_menus(&pool, since).await?),
+            ["late", "new"]
+        );
+
+        record_sync(&pool, MENUS, synced_at).await?;
+        let since = last_sync(&pool, MENUS).await?;
+        assert_eq!(
+            references(super::changed_menus(&pool, since).await?),
+            ["late", "new"]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test(migrator = "stor::db::MIGRATOR")]
+    async fn changed_meals(pool: PgPool) -> anyhow::Result<()> {
+        let now = OffsetDateTime::now_utc();
+        let today = now.to_timezone(crate::TZ).date();
+        let menu = |reference: &str, title: &str, checked_at| Menu {
+            location: Some(Point::new(18.07, 59.33)),
+            created_at: Some(checked_at),
+            checked_at: Some(checked_at),
+            ..Menu::from_supplier(Supplier::Skolmaten, reference, title)
+        };
+        let old = menu("old", "Old School", now - Duration::days(2));
+        let new = menu("new", "New School", now);
+
+        for menu in [&old, &new] {
+            insert_menu(&pool, menu).await?;
+        }
+
+        let meals = [
+            (&old, today - Duration::days(1), "Soppa"),
+            (&old, today + Duration::days(1), "Fisk"),
+            (&old, today + Duration::days(14), "Pannkakor"),
+            (&new, today + Duration::days(1), "Köttbullar"),
+        ];
+
+        for (menu, date, meal) in meals {
+            sqlx::query("INSERT INTO meals (menu_id, date, meal) VALUES ($1, $2, $3)")
+                .bind(menu.id)
+                .bind(date)
+                .bind(meal)
+                .execute(&pool)
+                .await?;
+        }
+
+        let dates = today..=today + Duration::days(14);
+        let names = |meals: Vec<Meal>| {
+            let mut names = meals.into_iter().map(|m| m.meal).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            names(super::changed_meals(&pool, dates.clone(), None, 14).await?),
+            ["Fisk", "Köttbullar", "Pannkakor"]
+        );
+
+        // the old menu is unchanged, but its last day was not upcoming then
+        let since = Some(now - Duration::days(1));
+        let meals = super::changed_meals(&pool, dates, since, 14).await?;
+        let doc = serde_json::to_value(meals.iter().find(|m| m.menu_id == new.id).unwrap())?;
+        assert_eq!(names(meals), ["Köttbullar", "Pannkakor"]);
+
+        assert_eq!(
+            doc["id"],
+            meal_id(new.id, today + Duration::days(1), "Köttbullar").to_string()
+        );
+        assert_eq!(doc["title"], "New School");
+        assert_eq!(
+            doc["date_timestamp"],
+            (today + Duration::days(1))
+                .midnight()
+                .assume_utc()
+                .unix_timestamp()
+        );
+        assert_eq!(doc["_geo"]["lat"], 59.33);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! Prometheus metrics. Traces are sampled, so these are what tell how the
+//! scrapers are doing in aggregate.
+//!
+//! `munin serve` exposes them on `/metrics`; the other commands can push them
+//! to a Pushgateway once they are done.
+
+use std::{future::Future, net::SocketAddr};
+
+use axum::{routing::get, Router};
+use once_cell::sync::Lazy;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounterVec, TextEncoder,
+};
+use tracing::info;
+
+/// HTTP requests made by the client, by supplier, host and status (or
+/// `error` if no response was received).
+pub static HTTP_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "munin_http_requests_total",
+        "HTTP requests made to the suppliers",
+        &["supplier", "host", "status"]
+    )
+    .unwrap()
+});
+
+/// How long it takes to list the days of a menu, retries included.
+pub static SCRAPE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "munin_scrape_duration_seconds",
+        "Time spent listing the days of a menu",
+        &["supplier"],
+        exponential_buckets(0.05, 2., 12).unwrap()
+    )
+    .unwrap()
+});
+
+pub static DAYS_WRITTEN: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "munin_days_written_total",
+        "Days written to the database",
+        &["supplier"]
+    )
+    .unwrap()
+});
+
+/// Meals written to the database, by whether they were `seen` (changed or
+/// not), `inserted` or `deleted`.
+pub static MEALS_WRITTEN: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "munin_meals_written_total",
+        "Meals written to the database",
+        &["supplier", "change"]
+    )
+    .unwrap()
+});
+
+pub static MENUS_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "munin_menus_failed_total",
+        "Menus that could not be refreshed",
+        &["supplier", "kind"]
+    )
+    .unwrap()
+});
+
+/// Geosearches for the location of a menu, by whether a `match` was found,
+/// the best hit was `too_far` from the known location, or there was `none`.
+pub static GEOSEARCH: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "munin_geosearch_total",
+        "Geosearches for the location of a menu",
+        &["result"]
+    )
+    .unwrap()
+});
+
+/// The metrics in the Prometheus text format.
+#[must_use]
+pub fn encode() -> String {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .expect("failed to encode metrics");
+
+    String::from_utf8(buf).expect("metrics are not utf-8")
+}
+
+/// Serve the metrics on `/metrics` until `shutdown` completes.
+pub async fn serve(addr: SocketAddr, shutdown: impl Future<Output = ()>) -> anyhow::Result<()> {
+    let app = Router::new().route("/metrics", get(|| async { encode() }));
+
+    info!(%addr, "serving metrics");
+
+    axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await?;
+
+    Ok(())
+}
+
+/// Push the metrics to the Pushgateway at `url`, replacing the ones
+/// previously pushed by `job`.
+pub async fn push(url: &str, job: &str) -> reqwest::Result<()> {
+    let url = format!(
+        "{}/metrics/job/{}",
+        url.trim_end_matches('/'),
+        urlencoding::encode(job)
+    );
+
+    reqwest::Client::new()
+        .put(url)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            TextEncoder::new().format_type(),
+        )
+        .body(encode())
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn encode() {
+        super::GEOSEARCH.with_label_values(&["match"]).inc();
+
+        assert!(super::encode().contains("munin_geosearch_total{result=\"match\"}"));
+    }
+}
@@ -0,0 +1,456 @@
+//! Run the current parsers over archived responses, so that a parser fix
+//! can be applied to weeks that the suppliers no longer serve.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use sqlx::{Acquire, PgPool};
+use stor::{menu::Supplier, Day, Menu};
+use time::{macros::format_description, Date, Duration, OffsetDateTime};
+use tracing::{error, info, warn};
+
+use crate::{
+    archive::{Archive, Entry, Snapshot},
+    client::Client,
+    supplier::ListDays,
+};
+
+/// Archived requests for a menu that are further apart than this belong to
+/// different fetches.
+const SESSION_GAP: Duration = Duration::minutes(10);
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    #[arg(long = "archive", env = "MUNIN_ARCHIVE")]
+    archive_dir: PathBuf,
+
+    #[arg(long)]
+    supplier: Supplier,
+
+    /// Only reparse this menu.
+    #[arg(long)]
+    reference: Option<String>,
+
+    /// First date to reparse (YYYY-MM-DD).
+    #[arg(long, value_parser = parse_date)]
+    from: Date,
+
+    /// Last date to reparse (YYYY-MM-DD).
+    #[arg(long, value_parser = parse_date)]
+    to: Date,
+
+    /// Only report what would change.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+fn parse_date(s: &str) -> Result<Date, time::error::Parse> {
+    Date::parse(s, format_description!("[year]-[month]-[day]"))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Change {
+    date: Date,
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+/// The end of every fetch among `fetched_at`, which must be sorted.
+fn sessions(fetched_at: impl IntoIterator<Item = OffsetDateTime>) -> Vec<OffsetDateTime> {
+    let mut sessions: Vec<OffsetDateTime> = Vec::new();
+
+    for t in fetched_at {
+        match sessions.last_mut() {
+            Some(end) if t - *end <= SESSION_GAP => *end = t,
+            _ => sessions.push(t),
+        }
+    }
+
+    sessions
+}
+
+fn diff(date: Date, old: &[String], new: &[String]) -> Option<Change> {
+    let old = old.iter().collect::<BTreeSet<_>>();
+    let new = new.iter().collect::<BTreeSet<_>>();
+
+    let change = Change {
+        date,
+        added: new.difference(&old).map(|s| (*s).clone()).collect(),
+        removed: old.difference(&new).map(|s| (*s).clone()).collect(),
+    };
+
+    (!change.added.is_empty() || !change.removed.is_empty()).then_some(change)
+}
+
+/// Parse every archived fetch of a menu, later fetches taking precedence for
+/// the dates they include. Each fetch is replayed as of when it was made and
+/// for the dates it was made for, so that the requests are the same as the
+/// ones that were archived. Every day is returned with the time of the fetch
+/// it was parsed from.
+///
+/// A date that a fetch was recorded for, but that is not in the parsed
+/// response, is returned without any meals. Fetches that were archived
+/// before the dates were recorded are only trusted with the days they list.
+///
+/// Fails if none of the fetches that include any of `dates` could be
+/// replayed.
+async fn parse_menu(
+    archive: &Arc<Archive>,
+    entries: &[Entry],
+    supplier: Supplier,
+    reference: &str,
+    dates: (Date, Date),
+) -> anyhow::Result<BTreeMap<Date, (OffsetDateTime, Day)>> {
+    let fetches = entries
+        .iter()
+        .filter(|e| e.supplier == Some(supplier) && e.reference.as_deref() == Some(reference))
+        .collect::<Vec<_>>();
+
+    let mut days = BTreeMap::new();
+    let mut replayed = 0;
+    let mut last_error = None;
+
+    for as_of in sessions(fetches.iter().map(|e| e.fetched_at)) {
+        // responses archived before the dates were recorded are replayed for
+        // the requested dates, which only works for some suppliers
+        let recorded = fetches
+            .iter()
+            .rev()
+            .find(|e| e.fetched_at <= as_of)
+            .and_then(|e| e.dates.clone());
+        let fetched_for = recorded.clone().unwrap_or(dates.0..=dates.1);
+
+        if *fetched_for.end() < dates.0 || *fetched_for.start() > dates.1 {
+            continue;
+        }
+
+        let snapshot = Snapshot::new(archive.clone(), entries, as_of);
+        let client = Client::replay_snapshot(Arc::new(snapshot));
+
+        match crate::list_days(&client, supplier, reference, fetched_for).await {
+            Ok(ListDays { days: parsed, .. }) => {
+                if let Some(recorded) = recorded {
+                    let mut date = (*recorded.start()).max(dates.0);
+
+                    while date <= (*recorded.end()).min(dates.1) {
+                        days.insert(date, (as_of, Day::new(date, Vec::new())));
+                        date = date.next_day().context("date out of range")?;
+                    }
+                }
+
+                for day in parsed {
+                    if (dates.0..=dates.1).contains(&day.date) {
+                        days.insert(day.date, (as_of, day));
+                    }
+                }
+
+                replayed += 1;
+            }
+            Err(e) => {
+                warn!(%supplier, reference, %as_of, "failed to reparse: {e:#}");
+                last_error = Some(e);
+            }
+        }
+    }
+
+    match last_error {
+        Some(e) if replayed == 0 => Err(e.context("no archived fetch could be replayed")),
+        _ => Ok(days),
+    }
+}
+
+pub async fn reparse(opt: Args, pool: &PgPool) -> anyhow::Result<()> {
+    let archive = Arc::new(Archive::open(&opt.archive_dir)?);
+    let entries = archive.entries()?;
+
+    let references = entries
+        .iter()
+        .filter(|e| e.supplier == Some(opt.supplier))
+        .filter_map(|e| e.reference.as_deref())
+        .filter(|r| opt.reference.is_none() || opt.reference.as_deref() == Some(*r))
+        .collect::<BTreeSet<_>>();
+
+    info!(menus = references.len(), "reparsing archived responses");
+
+    let mut conn = pool.acquire().await?;
+    let mut total_changes = 0;
+    let mut failed = 0;
+
+    for reference in references {
+        let menu_id = Menu::from_supplier(opt.supplier, reference, "").id;
+
+        let exists =
+            sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM menus WHERE id = $1)")
+                .bind(menu_id)
+                .fetch_one(&mut conn)
+                .await?;
+        if !exists {
+            warn!(reference, "menu not found in the database, skipping");
+            continue;
+        }
+
+        let days = match parse_menu(
+            &archive,
+            &entries,
+            opt.supplier,
+            reference,
+            (opt.from, opt.to),
+        )
+        .await
+        {
+            Ok(days) => days,
+            Err(e) => {
+                error!(reference, "failed to reparse: {e:#}");
+                failed += 1;
+                continue;
+            }
+        };
+
+        let mut stored: BTreeMap<Date, Vec<String>> = BTreeMap::new();
+        let rows = sqlx::query_as::<_, (Date, String)>(
+            "SELECT date, meal FROM meals WHERE menu_id = $1 AND date BETWEEN $2 AND $3",
+        )
+        .bind(menu_id)
+        .bind(opt.from)
+        .bind(opt.to)
+        .fetch_all(&mut conn)
+        .await?;
+        for (date, meal) in rows {
+            stored.entry(date).or_default().push(meal);
+        }
+
+        let changes = days
+            .values()
+            .filter_map(|(_, d)| {
+                diff(
+                    d.date,
+                    stored.get(&d.date).map_or(&[], Vec::as_slice),
+                    &d.meals,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for change in &changes {
+            println!("{} {reference} {}", opt.supplier, change.date);
+            for meal in &change.removed {
+                println!("  - {meal}");
+            }
+            for meal in &change.added {
+                println!("  + {meal}");
+            }
+        }
+
+        let unknown = stored.keys().filter(|d| !days.contains_key(d)).count();
+        if unknown > 0 {
+            warn!(
+                reference,
+                days = unknown,
+                "no archived fetch was made for some stored days, leaving them alone"
+            );
+        }
+
+        total_changes += changes.len();
+
+        if opt.dry_run || changes.is_empty() {
+            continue;
+        }
+
+        // the days are written as of the fetches they were parsed from, so
+        // that the history keeps when the meals were actually seen
+        let changed = changes.iter().map(|c| c.date).collect::<BTreeSet<_>>();
+        let mut fetches: BTreeMap<OffsetDateTime, Vec<Day>> = BTreeMap::new();
+        for (fetched_at, day) in days.into_values() {
+            if changed.contains(&day.date) {
+                fetches.entry(fetched_at).or_default().push(day);
+            }
+        }
+
+        let mut txn = conn.begin().await?;
+
+        for (fetched_at, days) in fetches {
+            crate::index::write_days(&mut txn, menu_id, days, fetched_at)
+                .await
+                .with_context(|| format!("failed to write {reference}"))?;
+        }
+
+        txn.commit().await?;
+    }
+
+    info!(
+        days = total_changes,
+        dry_run = opt.dry_run,
+        "reparsed archived responses"
+    );
+
+    anyhow::ensure!(failed == 0, "failed to reparse {failed} menus");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use stor::menu::Supplier;
+    use tempdir::TempDir;
+    use time::macros::{date, datetime};
+
+    use crate::archive::{Archive, Entry};
+
+    use super::Change;
+
+    #[tokio::test]
+    async fn parse_menu() {
+        let dir = TempDir::new("archive").unwrap();
+        let archive = Arc::new(Archive::open(dir.path()).unwrap());
+        let body = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/fixtures/sabis/carnegie.html"
+        ))
+        .unwrap();
+
+        archive
+            .record(&Entry {
+                fetched_at: datetime!(2023-02-06 08:00 UTC),
+                supplier: Some(Supplier::Sabis),
+                reference: Some("carnegie".to_owned()),
+                dates: None,
+                method: "GET".to_owned(),
+                url: "https://www.sabis.se/restauranger-cafeer/vara-foretagsrestauranger/carnegie/"
+                    .to_owned(),
+                request_body: None,
+                status: 200,
+                headers: [(
+                    "date".to_owned(),
+                    "Mon, 06 Feb 2023 08:00:00 GMT".to_owned(),
+                )]
+                .into(),
+                hash: archive.put(&body).unwrap(),
+            })
+            .unwrap();
+
+        let days = super::parse_menu(
+            &archive,
+            &archive.entries().unwrap(),
+            Supplier::Sabis,
+            "carnegie",
+            (date!(2023 - 02 - 07), date!(2023 - 02 - 28)),
+        )
+        .await
+        .unwrap();
+
+        // monday is outside of the range, and the dates of the fetch were
+        // not recorded, so the other weeks are not included
+        assert_eq!(days.len(), 4);
+        assert_eq!(*days.keys().next().unwrap(), date!(2023 - 02 - 07));
+        assert!(days
+            .values()
+            .all(|(fetched_at, _)| *fetched_at == datetime!(2023-02-06 08:00 UTC)));
+    }
+
+    #[tokio::test]
+    async fn parse_menu_as_fetched() {
+        let dir = TempDir::new("archive").unwrap();
+        let archive = Arc::new(Archive::open(dir.path()).unwrap());
+        let reference = "c=10242&p=1594&m=2161&r=21";
+
+        // matilda asks for weeks relative to the current one, so the
+        // responses can only be found by replaying as of the fetch
+        let record = |week: u8| {
+            let body = std::fs::read(format!(
+                "{}/fixtures/matilda/week-{week}.html",
+                env!("CARGO_MANIFEST_DIR")
+            ))
+            .unwrap();
+
+            archive
+                .record(&Entry {
+                    fetched_at: datetime!(2023-02-06 08:00 UTC),
+                    supplier: Some(Supplier::Matilda),
+                    reference: Some(reference.to_owned()),
+                    dates: Some(date!(2023 - 02 - 06)..=date!(2023 - 02 - 19)),
+                    method: "GET".to_owned(),
+                    url: format!("https://webmenu.foodit.se/?{reference}&v=Week&w={week}"),
+                    request_body: None,
+                    status: 200,
+                    headers: Default::default(),
+                    hash: archive.put(&body).unwrap(),
+                })
+                .unwrap();
+        };
+
+        record(0);
+
+        let parse = |dates| {
+            let entries = archive.entries().unwrap();
+            let archive = archive.clone();
+            async move {
+                super::parse_menu(&archive, &entries, Supplier::Matilda, reference, dates).await
+            }
+        };
+
+        // the second week is missing
+        assert!(parse((date!(2023 - 02 - 07), date!(2023 - 02 - 17)))
+            .await
+            .is_err());
+
+        record(1);
+
+        let days = parse((date!(2023 - 02 - 07), date!(2023 - 02 - 17)))
+            .await
+            .unwrap();
+        let served = days.values().filter(|(_, d)| !d.meals.is_empty());
+        assert_eq!(served.count(), 9);
+        assert_eq!(*days.keys().next().unwrap(), date!(2023 - 02 - 07));
+        assert_eq!(*days.keys().last().unwrap(), date!(2023 - 02 - 17));
+
+        // the weekend was fetched, but there is nothing served
+        assert_eq!(days.len(), 11);
+        assert!(days[&date!(2023 - 02 - 11)].1.meals.is_empty());
+
+        // no fetch covers these dates, which is not an error
+        assert!(parse((date!(2023 - 03 - 01), date!(2023 - 03 - 31)))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn sessions() {
+        let sessions = super::sessions([
+            datetime!(2023-02-06 04:00:00 UTC),
+            datetime!(2023-02-06 04:00:02 UTC),
+            datetime!(2023-02-06 04:09:00 UTC),
+            datetime!(2023-02-07 04:00:00 UTC),
+        ]);
+
+        assert_eq!(
+            sessions,
+            [
+                datetime!(2023-02-06 04:09:00 UTC),
+                datetime!(2023-02-07 04:00:00 UTC)
+            ]
+        );
+    }
+
+    #[test]
+    fn diff() {
+        let date = date!(2023 - 02 - 06);
+        let old = ["Pannkakor".to_owned(), "Fisk".to_owned()];
+        let new = ["Pannkakor".to_owned(), "Köttbullar".to_owned()];
+
+        assert_eq!(
+            super::diff(date, &old, &new),
+            Some(Change {
+                date,
+                added: vec!["Köttbullar".to_owned()],
+                removed: vec!["Fisk".to_owned()],
+            })
+        );
+        assert_eq!(super::diff(date, &old, &old), None);
+    }
+}
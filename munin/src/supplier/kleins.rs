@@ -1,6 +1,7 @@
 use std::ops::RangeInclusive;
 
-use reqwest::{header::USER_AGENT, Client, IntoUrl, Response};
+use async_trait::async_trait;
+use reqwest::{header::USER_AGENT, IntoUrl, Response};
 use select::{
     document::Document,
     node::Node,
@@ -10,9 +11,14 @@ use stor::menu::{Menu, Supplier};
 use time::Date;
 use tracing::instrument;
 
-use crate::{mashie, util::last_path_segment, Error, Result};
+use crate::{
+    client::{Client, Page},
+    mashie,
+    util::last_path_segment,
+    Result,
+};
 
-use super::ListDays;
+use super::{Canary, ListDays};
 
 #[derive(Debug)]
 struct School {
@@ -73,14 +79,14 @@ async fn query_school(client: &Client, school_slug: &str) -> Result<QuerySchoolR
         "https://www.kleinskitchen.se/skolor/{}",
         urlencoding::encode(school_slug)
     );
-    let html = fetch(client, &url).await?.text().await?;
-    let doc = Document::from(html.as_str());
+    let page = Page::read(fetch(client, &url).await?).await?;
+    let doc = Document::from(page.body.as_str());
 
     let menu_url = doc
         .find(Name("iframe"))
         .next()
         .and_then(|n| extract_menu_url(&n))
-        .ok_or_else(|| Error::scrape_error_with_context("no iframe found", Some(&html)))?;
+        .ok_or_else(|| page.error("no iframe found"))?;
 
     Ok(QuerySchoolResponse { menu_url })
 }
@@ -95,7 +101,7 @@ pub async fn list_days(
         let res = query_school(client, menu_slug).await?;
         res.menu_url
     };
-    let html = reqwest::get(&menu_url).await?.text().await?;
+    let html = client.get(&menu_url).send().await?.text().await?;
     let doc = Document::from(html.as_str());
     let days = mashie::scrape_days(&doc)
         .filter(|day| dates.contains(&day.date))
@@ -107,29 +113,65 @@ pub async fn list_days(
     })
 }
 
+pub struct Kleins;
+
+#[async_trait]
+impl super::Supplier for Kleins {
+    fn id(&self) -> Supplier {
+        Supplier::Kleins
+    }
+
+    fn hosts(&self) -> &'static [&'static str] {
+        &["https://www.kleinskitchen.se"]
+    }
+
+    fn canary(&self) -> Canary {
+        Canary {
+            reference: "forskolan-pingvinen",
+            classes: mashie::CLASSES,
+        }
+    }
+
+    async fn list_menus(&self, client: &Client) -> Result<Vec<Menu>> {
+        list_menus(client).await
+    }
+
+    async fn list_days(
+        &self,
+        client: &Client,
+        reference: &str,
+        dates: RangeInclusive<Date>,
+    ) -> Result<ListDays> {
+        list_days(client, reference, dates).await
+    }
+}
+
 const UA: &str = "Mozilla/5.0 (Windows NT 6.1; Win64; x64; rv:47.0) Gecko/20100101 Firefox/47.0";
 
-async fn fetch(client: &Client, url: impl IntoUrl) -> reqwest::Result<Response> {
+async fn fetch(client: &Client, url: impl IntoUrl) -> Result<Response, crate::client::Error> {
     client.get(url).header(USER_AGENT, UA).send().await
 }
 
 #[cfg(test)]
 mod tests {
-    use reqwest::{Client, StatusCode};
+    use reqwest::StatusCode;
+    use stor::menu::FailureKind;
     use time::macros::date;
 
-    use crate::supplier::ListDays;
+    use crate::{client::fixture, error::ScrapeError, supplier::ListDays};
 
     #[tokio::test]
     async fn list_schools() {
-        let schools = super::list_schools(&Client::new()).await.unwrap();
+        let schools = super::list_schools(&fixture("kleins")).await.unwrap();
 
-        assert!(schools.len() > 50);
+        assert_eq!(schools.len(), 3);
+        assert_eq!(schools[0].slug, "forskolan-pingvinen");
     }
 
     #[tokio::test]
     async fn query_school() {
-        let res = super::query_school(&Client::new(), "viktor-rydberg-grundskola-jarlaplan")
+        let client = fixture("kleins");
+        let res = super::query_school(&client, "viktor-rydberg-grundskola-jarlaplan")
             .await
             .unwrap();
 
@@ -139,31 +181,35 @@ mod tests {
         );
 
         assert!(
-            super::query_school(&Client::new(), "viktor-rydberg-grundskola-jarlaplan?a=evil")
+            super::query_school(&client, "viktor-rydberg-grundskola-jarlaplan?a=evil")
                 .await
                 .is_err()
         );
-        assert!(super::query_school(&Client::new(), "nonexistent")
+
+        let e = super::query_school(&client, "nonexistent")
             .await
-            .is_err());
+            .unwrap_err();
+        let e = e.downcast_ref::<ScrapeError>().unwrap();
+        assert_eq!(e.status, Some(StatusCode::NOT_FOUND));
+        assert_eq!(e.kind(), FailureKind::Permanent);
     }
 
     #[tokio::test]
     async fn list_days() {
         let ListDays { days, .. } = super::list_days(
-            &Client::new(),
+            &fixture("kleins"),
             "forskolan-pingvinen",
             date!(1970 - 01 - 01)..=date!(2077 - 01 - 01),
         )
         .await
         .unwrap();
 
-        assert!(!days.is_empty());
+        assert_eq!(days.len(), 3);
     }
 
     #[tokio::test]
     async fn fetch() {
-        let res = super::fetch(&Client::new(), "https://www.kleinskitchen.se/skolor/")
+        let res = super::fetch(&fixture("kleins"), "https://www.kleinskitchen.se/skolor/")
             .await
             .unwrap();
 
@@ -1,7 +1,7 @@
 use std::{borrow::Cow, fmt::Display, iter, ops::RangeInclusive, str::FromStr};
 
+use async_trait::async_trait;
 use futures::{stream, StreamExt};
-use reqwest::Client;
 use select::{
     document::Document,
     node::Node,
@@ -9,13 +9,17 @@ use select::{
 };
 use serde::{Deserialize, Serialize};
 use stor::{meal::sanitize_meal_value, menu::Supplier, Day, Menu};
-use time::{Date, Duration, OffsetDateTime, Weekday};
+use time::{Date, Duration, Weekday};
 use time_tz::OffsetDateTimeExt;
 use tracing::{error, instrument, trace};
 
-use crate::{util::parse_weekday, Error, Result};
+use crate::{
+    client::{Client, Page, RateLimit},
+    util::parse_weekday,
+    Error, Result,
+};
 
-use super::ListDays;
+use super::{Canary, ListDays};
 
 #[derive(Debug, Clone, Serialize)]
 struct Region {
@@ -28,12 +32,12 @@ struct Region {
 }
 
 #[derive(Debug, Clone, Serialize)]
-struct Municipality<'r> {
+struct Municipality {
     #[serde(rename = "m")]
     id: u32,
 
     #[serde(flatten)]
-    region: &'r Region,
+    region: Region,
 
     #[allow(dead_code)]
     #[serde(skip)]
@@ -41,24 +45,24 @@ struct Municipality<'r> {
 }
 
 #[derive(Debug, Clone, Serialize)]
-struct Part<'m> {
+struct Part {
     #[serde(rename = "p")]
     id: u32,
 
     #[serde(flatten)]
-    municipality: &'m Municipality<'m>,
+    municipality: Municipality,
 
     #[serde(skip)]
     name: String,
 }
 
 #[derive(Debug, Clone, Serialize)]
-struct Customer<'p> {
+struct Customer {
     #[serde(rename = "c")]
     id: u32,
 
     #[serde(flatten)]
-    part: &'p Part<'p>,
+    part: Part,
 
     #[serde(skip)]
     name: String,
@@ -111,7 +115,7 @@ trait MatildaMenu {
     }
 }
 
-impl MatildaMenu for Part<'_> {
+impl MatildaMenu for Part {
     fn title(&self) -> Cow<'_, str> {
         Cow::Borrowed(&self.name)
     }
@@ -126,7 +130,7 @@ impl MatildaMenu for Part<'_> {
     }
 }
 
-impl MatildaMenu for Customer<'_> {
+impl MatildaMenu for Customer {
     fn title(&self) -> Cow<'_, str> {
         format!("{} ({})", self.name, self.part.name).into()
     }
@@ -142,15 +146,19 @@ impl MatildaMenu for Customer<'_> {
 }
 
 #[instrument(level = "trace", skip(client))]
-async fn get_doc<T: Serialize + std::fmt::Debug>(client: &Client, query: &T) -> Result<Document> {
-    let res = client
+async fn get_page<T: Serialize + std::fmt::Debug>(client: &Client, query: &T) -> Result<Page> {
+    let page = client
         .get("https://webmenu.foodit.se/")
         .query(query)
-        .send()
+        .page()
         .await?;
-    trace!("GET {}", res.url());
-    let html = res.text().await?;
-    Ok(Document::from(html.as_str()))
+    trace!("GET {}", page.url);
+    Ok(page)
+}
+
+async fn get_doc<T: Serialize + std::fmt::Debug>(client: &Client, query: &T) -> Result<Document> {
+    let page = get_page(client, query).await?;
+    Ok(Document::from(page.body.as_str()))
 }
 
 fn scrape_options<'a>(
@@ -180,28 +188,26 @@ async fn list_regions(client: &Client) -> Result<Vec<Region>> {
 }
 
 #[instrument(level = "debug", skip(client))]
-async fn list_municipalities<'r>(
-    client: &Client,
-    region: &'r Region,
-) -> Result<Vec<Municipality<'r>>> {
-    let doc = get_doc(client, &region).await?;
+async fn list_municipalities(client: &Client, region: &Region) -> Result<Vec<Municipality>> {
+    let doc = get_doc(client, region).await?;
     let municipalities = scrape_options(&doc, "MunicipalityList")
-        .map(|(id, name)| Municipality { id, region, name })
+        .map(|(id, name)| Municipality {
+            id,
+            region: region.clone(),
+            name,
+        })
         .collect();
 
     Ok(municipalities)
 }
 
 #[instrument(level = "debug", skip(client))]
-async fn list_parts<'m>(
-    client: &Client,
-    municipality: &'m Municipality<'m>,
-) -> Result<Vec<Part<'m>>> {
+async fn list_parts(client: &Client, municipality: &Municipality) -> Result<Vec<Part>> {
     let doc = get_doc(client, municipality).await?;
     let parts = scrape_options(&doc, "PartList")
         .map(|(id, name)| Part {
             id,
-            municipality,
+            municipality: municipality.clone(),
             name,
         })
         .collect();
@@ -210,10 +216,14 @@ async fn list_parts<'m>(
 }
 
 #[instrument(level = "debug", skip(client))]
-async fn list_customers<'p>(client: &Client, part: &'p Part<'p>) -> Result<Vec<Customer<'p>>> {
+async fn list_customers(client: &Client, part: &Part) -> Result<Vec<Customer>> {
     let doc = get_doc(client, part).await?;
     let customers = scrape_options(&doc, "CustomerList")
-        .map(|(id, name)| Customer { id, part, name })
+        .map(|(id, name)| Customer {
+            id,
+            part: part.clone(),
+            name,
+        })
         .collect();
 
     Ok(customers)
@@ -222,7 +232,7 @@ async fn list_customers<'p>(client: &Client, part: &'p Part<'p>) -> Result<Vec<C
 const CONCURRENT_REQUESTS: usize = 16;
 
 #[instrument(level = "debug", skip(client))]
-async fn menus_in_part<'p>(client: &Client, part: Part<'p>) -> Result<Vec<Menu>> {
+async fn menus_in_part(client: &Client, part: Part) -> Result<Vec<Menu>> {
     let customers = list_customers(client, &part).await?;
 
     if customers.is_empty() {
@@ -233,12 +243,9 @@ async fn menus_in_part<'p>(client: &Client, part: Part<'p>) -> Result<Vec<Menu>>
 }
 
 #[instrument(level = "debug", skip(client))]
-async fn menus_in_municipality<'m>(
-    client: &Client,
-    municipality: &'m Municipality<'m>,
-) -> Result<Vec<Menu>> {
+async fn menus_in_municipality(client: &Client, municipality: Municipality) -> Result<Vec<Menu>> {
     let mut menus = vec![];
-    let parts = list_parts(client, municipality).await?;
+    let parts = list_parts(client, &municipality).await?;
 
     let mut menus_stream = stream::iter(parts)
         .map(|p| menus_in_part(client, p))
@@ -252,11 +259,11 @@ async fn menus_in_municipality<'m>(
 }
 
 #[instrument(level = "debug", skip(client))]
-async fn menus_in_region(client: &Client, region: &Region) -> Result<Vec<Menu>> {
+async fn menus_in_region(client: &Client, region: Region) -> Result<Vec<Menu>> {
     let mut menus = vec![];
-    let municipalities = list_municipalities(client, region).await?;
+    let municipalities = list_municipalities(client, &region).await?;
 
-    let mut parts_stream = stream::iter(municipalities.iter())
+    let mut parts_stream = stream::iter(municipalities)
         .map(|m| menus_in_municipality(client, m))
         .buffer_unordered(CONCURRENT_REQUESTS);
 
@@ -273,7 +280,7 @@ pub async fn list_menus(client: &Client) -> Result<Vec<Menu>> {
 
     let regions = list_regions(client).await?;
 
-    let mut menus_stream = stream::iter(regions.iter())
+    let mut menus_stream = stream::iter(regions)
         .map(|region| menus_in_region(client, region))
         .buffer_unordered(CONCURRENT_REQUESTS);
 
@@ -323,18 +330,19 @@ async fn days_by_week(client: &Client, menu: &MenuQuery, week_offset: i64) -> Re
         week_offset,
     };
 
-    let doc = get_doc(client, &q).await?;
+    let page = get_page(client, &q).await?;
+    let doc = Document::from(page.body.as_str());
 
     let year = doc
         .find(Attr("id", "Year"))
         .next()
         .and_then(|n| n.attr("value")?.parse().ok())
-        .ok_or_else(|| Error::scrape_error("couldn't get year"))?;
+        .ok_or_else(|| page.error("couldn't get year"))?;
     let week_num = doc
         .find(Attr("id", "WeekPageWeekNo"))
         .next()
         .and_then(|n| n.attr("value")?.parse().ok())
-        .ok_or_else(|| Error::scrape_error("couldn't get week number"))?;
+        .ok_or_else(|| page.error("couldn't get week number"))?;
 
     let days = doc
         .find(Name("li").and(Class("li-menu")))
@@ -351,7 +359,7 @@ pub async fn list_days(
     menu: &MenuQuery,
     dates: RangeInclusive<Date>,
 ) -> Result<ListDays> {
-    let today = OffsetDateTime::now_utc().to_timezone(crate::TZ).date();
+    let today = client.now().to_timezone(crate::TZ).date();
 
     let offsets = stream::iter(week_offsets(today, dates).unwrap());
     let mut days_stream = offsets
@@ -370,6 +378,53 @@ pub async fn list_days(
     })
 }
 
+pub struct Matilda;
+
+fn parse_query(reference: &str) -> Result<MenuQuery> {
+    reference
+        .parse()
+        .map_err(|_| Error::InvalidReference.into())
+}
+
+#[async_trait]
+impl super::Supplier for Matilda {
+    fn id(&self) -> Supplier {
+        Supplier::Matilda
+    }
+
+    fn hosts(&self) -> &'static [&'static str] {
+        &["https://webmenu.foodit.se"]
+    }
+
+    fn canary(&self) -> Canary {
+        Canary {
+            reference: "c=10242&p=1594&m=2161&r=21",
+            classes: &["li-menu", "date-container", "meal-text"],
+        }
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        RateLimit::new(4, std::time::Duration::from_millis(100))
+    }
+
+    fn validate_reference(&self, reference: &str) -> Result<()> {
+        parse_query(reference).map(|_| ())
+    }
+
+    async fn list_menus(&self, client: &Client) -> Result<Vec<Menu>> {
+        list_menus(client).await
+    }
+
+    async fn list_days(
+        &self,
+        client: &Client,
+        reference: &str,
+        dates: RangeInclusive<Date>,
+    ) -> Result<ListDays> {
+        list_days(client, &parse_query(reference)?, dates).await
+    }
+}
+
 fn rewind_to_weekday(mut date: Date, weekday: Weekday) -> Option<Date> {
     while date.weekday() != weekday {
         date = date.previous_day()?;
@@ -401,18 +456,28 @@ pub(super) fn week_offsets(
 
 #[cfg(test)]
 mod tests {
-    use reqwest::Client;
-    use time::{macros::date, Duration, OffsetDateTime};
+    use time::{macros::date, Duration, OffsetDateTime, Weekday};
+    use time_tz::OffsetDateTimeExt;
 
-    use crate::supplier::ListDays;
+    use crate::{client::fixture, supplier::ListDays};
 
     use super::MenuQuery;
 
     #[tokio::test]
     async fn list_menus() {
-        let menus = super::list_menus(&Client::new()).await.unwrap();
-
-        assert!(menus.len() > 500);
+        let mut menus = super::list_menus(&fixture("matilda")).await.unwrap();
+        menus.sort_by(|a, b| a.title.cmp(&b.title));
+
+        let titles = menus.iter().map(|m| m.title.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            titles,
+            [
+                "Förskolan Ekorren (Förskolor)",
+                "Förskolan Myran (Förskolor)",
+                "Grundskolor"
+            ]
+        );
+        assert_eq!(menus[0].supplier_reference, "c=10242&p=1594&m=2161&r=21");
     }
 
     #[tokio::test]
@@ -423,17 +488,20 @@ mod tests {
             municipality: 2161,
             region: 21,
         };
-        let first = OffsetDateTime::now_utc() - Duration::weeks(1);
+        // week offsets are relative to the current week
+        let today = OffsetDateTime::now_utc().to_timezone(crate::TZ).date();
+        let first = super::rewind_to_weekday(today, Weekday::Monday).unwrap();
 
         let ListDays { days, .. } = super::list_days(
-            &Client::new(),
+            &fixture("matilda"),
             &menu,
-            first.date()..=(first + Duration::days(30)).date(),
+            first..=first + Duration::days(13),
         )
         .await
         .unwrap();
 
-        assert!(days.len() >= 7);
+        assert_eq!(days.len(), 10);
+        assert!(days.iter().all(|d| !d.meals.is_empty()));
     }
 
     #[test]
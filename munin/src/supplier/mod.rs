@@ -1,4 +1,13 @@
-use stor::{menu::Patch, Day};
+use std::ops::RangeInclusive;
+
+use async_trait::async_trait;
+use stor::{config::Config, menu::Patch, Day, Menu};
+use time::Date;
+
+use crate::{
+    client::{Client, RateLimit},
+    Error, Result,
+};
 
 pub mod kleins;
 pub mod matilda;
@@ -12,3 +21,134 @@ pub struct ListDays {
     pub menu: Patch,
     pub days: Vec<Day>,
 }
+
+/// What a supplier is able to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// The supplier can enumerate all of its menus.
+    pub list_menus: bool,
+    /// [`Supplier::list_days`] honors the requested date range. Suppliers
+    /// without this capability only return what is currently published
+    /// (usually the current week).
+    pub date_range: bool,
+}
+
+/// A menu that is known to publish meals, checked by `munin check` to tell a
+/// scraper that broke from a menu that happens to be empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canary {
+    pub reference: &'static str,
+    /// Classes of the elements the scraper looks for. Each must be present on
+    /// one of the pages fetched for the menu.
+    pub classes: &'static [&'static str],
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            list_menus: true,
+            date_range: true,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Supplier: Send + Sync {
+    /// The identifier under which menus of this supplier are stored.
+    fn id(&self) -> stor::menu::Supplier;
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Base URLs of the hosts this supplier makes requests to.
+    fn hosts(&self) -> &'static [&'static str];
+
+    /// How hard we may hit each of [`Supplier::hosts`].
+    fn rate_limit(&self) -> RateLimit {
+        RateLimit::default()
+    }
+
+    /// Check that `reference` is a well-formed supplier reference, without
+    /// making any requests.
+    fn validate_reference(&self, reference: &str) -> Result<()> {
+        let _ = reference;
+        Ok(())
+    }
+
+    /// See [`Canary`].
+    fn canary(&self) -> Canary;
+
+    async fn list_menus(&self, client: &Client) -> Result<Vec<Menu>>;
+
+    async fn list_days(
+        &self,
+        client: &Client,
+        reference: &str,
+        dates: RangeInclusive<Date>,
+    ) -> Result<ListDays>;
+}
+
+/// All known suppliers.
+pub static SUPPLIERS: &[&dyn Supplier] = &[
+    &skolmaten::Skolmaten,
+    &sodexo::Sodexo,
+    &mpi::Mpi,
+    &kleins::Kleins,
+    &sabis::Sabis,
+    &matilda::Matilda,
+];
+
+/// Get the implementation of a supplier.
+pub fn get(id: stor::menu::Supplier) -> Result<&'static dyn Supplier, Error> {
+    SUPPLIERS
+        .iter()
+        .copied()
+        .find(|s| s.id() == id)
+        .ok_or(Error::UnknownSupplier(id))
+}
+
+/// Wrap `inner` in a client that respects the rate limits of all suppliers,
+/// lowered to the concurrency in `config`, and sends their requests to the
+/// hosts in `config`.
+#[must_use]
+pub fn client(inner: reqwest::Client, config: &Config) -> Client {
+    SUPPLIERS.iter().fold(Client::from(inner), |client, s| {
+        let config = config.supplier(s.id());
+        let mut limit = s.rate_limit();
+
+        if let Some(concurrency) = config.concurrency {
+            limit.concurrency = limit.concurrency.min(concurrency);
+        }
+
+        s.hosts().iter().fold(client, |client, host| {
+            let client = client.with_rate_limit(host, limit);
+
+            match config.host.as_deref() {
+                Some(to) => client.with_host(host, to).with_rate_limit(to, limit),
+                None => client,
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn registry_is_complete() {
+        for id in stor::menu::Supplier::iter() {
+            assert_eq!(super::get(id).unwrap().id(), id);
+        }
+
+        assert_eq!(super::SUPPLIERS.len(), stor::menu::Supplier::iter().count());
+    }
+
+    #[test]
+    fn canaries_are_valid() {
+        for s in super::SUPPLIERS {
+            assert!(s.validate_reference(s.canary().reference).is_ok());
+        }
+    }
+}
@@ -2,18 +2,24 @@ use stor::menu::Supplier;
 
 use crate::mashie::mashie_impl;
 
-mashie_impl!("https://mpi.mashie.com", Supplier::Mpi);
+mashie_impl!(
+    Mpi,
+    "https://mpi.mashie.com",
+    Supplier::Mpi,
+    "e4e189ac-f42d-4f82-89a8-aef300d00f33"
+);
 
 #[cfg(test)]
 mod tests {
-    use reqwest::Client;
     use time::OffsetDateTime;
 
+    use crate::client::fixture;
+
     #[tokio::test]
     async fn sodra_latin() {
         let today = OffsetDateTime::now_utc().date();
         let res = super::list_days(
-            &Client::new(),
+            &fixture("mpi"),
             "e4e189ac-f42d-4f82-89a8-aef300d00f33",
             today..=today,
         )
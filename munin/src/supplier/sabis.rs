@@ -1,21 +1,28 @@
-use reqwest::{
-    header::{self, HeaderMap},
-    Client,
-};
+use std::ops::RangeInclusive;
+
+use async_trait::async_trait;
+use reqwest::header::{self, HeaderMap};
 use select::{
     document::Document,
     predicate::{Class, Name},
 };
-use stor::Day;
+use stor::{menu::Supplier, Day, Menu};
 use time::{Date, OffsetDateTime};
 use time_tz::OffsetDateTimeExt;
+use tracing::instrument;
 
-use crate::{util::parse_weekday, Error, Result};
+use crate::{
+    client::{Client, Page},
+    util::parse_weekday,
+    Result,
+};
 
-use super::ListDays;
+use super::{Canary, Capabilities, ListDays};
 
 pub const TZ: &time_tz::Tz = time_tz::timezones::db::europe::STOCKHOLM;
 
+const DIRECTORY_PATH: &str = "/restauranger-cafeer/vara-foretagsrestauranger/";
+
 fn http_date(headers: &HeaderMap) -> Option<OffsetDateTime> {
     let s = headers.get(header::DATE)?.to_str().ok()?;
     httpdate::parse_http_date(s).ok().map(Into::into)
@@ -26,18 +33,57 @@ fn weeknum(doc: &Document) -> Option<u8> {
     title.split_whitespace().rev().next()?.parse().ok()
 }
 
+/// Extract the slug from a link to a restaurant page.
+fn restaurant_slug(href: &str) -> Option<&str> {
+    let path = href.strip_prefix("https://www.sabis.se").unwrap_or(href);
+    let slug = path.strip_prefix(DIRECTORY_PATH)?.trim_end_matches('/');
+
+    if slug.is_empty() || slug.contains(['/', '?', '#']) {
+        None
+    } else {
+        Some(slug)
+    }
+}
+
+/// List the restaurants in the directory. They are located by the
+/// geosearch when indexing, if it is enabled, as the directory is not known
+/// to include their locations.
+#[instrument(name = "sabis::list_menus", err, skip(client))]
+pub async fn list_menus(client: &Client) -> Result<Vec<Menu>> {
+    let url = format!("https://www.sabis.se{DIRECTORY_PATH}");
+    let html = client.get(url).send().await?.text().await?;
+    let doc = Document::from(html.as_str());
+
+    let mut menus: Vec<Menu> = Vec::new();
+
+    for node in doc.find(Name("a")) {
+        let Some(slug) = node.attr("href").and_then(restaurant_slug) else {
+            continue;
+        };
+
+        // the cards link to the restaurant both from the image and the title
+        let title = node.text().split_whitespace().collect::<Vec<_>>().join(" ");
+        if title.is_empty() || menus.iter().any(|m| m.supplier_reference == slug) {
+            continue;
+        }
+
+        menus.push(Menu::from_supplier(Supplier::Sabis, slug, title));
+    }
+
+    Ok(menus)
+}
+
 pub async fn list_days(client: &Client, restaurant: &str) -> Result<ListDays> {
-    let url =
-        format!("https://www.sabis.se/restauranger-cafeer/vara-foretagsrestauranger/{restaurant}/");
+    let url = format!("https://www.sabis.se{DIRECTORY_PATH}{restaurant}/");
     let res = client.get(url).send().await?;
     let date = http_date(res.headers())
-        .unwrap_or_else(OffsetDateTime::now_utc)
+        .unwrap_or_else(|| client.now())
         .to_timezone(TZ);
 
-    let html = res.text().await?;
-    let doc = Document::from(html.as_str());
+    let page = Page::read(res).await?;
+    let doc = Document::from(page.body.as_str());
 
-    let week = weeknum(&doc).ok_or_else(|| Error::scrape_error("failed to extract week number"))?;
+    let week = weeknum(&doc).ok_or_else(|| page.error("failed to extract week number"))?;
 
     let days: Vec<Day> = doc
         .find(Class("menu-block__dishes"))
@@ -66,14 +112,93 @@ pub async fn list_days(client: &Client, restaurant: &str) -> Result<ListDays> {
     })
 }
 
+pub struct Sabis;
+
+#[async_trait]
+impl super::Supplier for Sabis {
+    fn id(&self) -> Supplier {
+        Supplier::Sabis
+    }
+
+    fn hosts(&self) -> &'static [&'static str] {
+        &["https://www.sabis.se"]
+    }
+
+    fn canary(&self) -> Canary {
+        Canary {
+            reference: "carnegie",
+            classes: &[
+                "menu-block__title",
+                "menu-block__day-title",
+                "menu-block__dishes",
+            ],
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            date_range: false,
+            ..Default::default()
+        }
+    }
+
+    async fn list_menus(&self, client: &Client) -> Result<Vec<Menu>> {
+        list_menus(client).await
+    }
+
+    async fn list_days(
+        &self,
+        client: &Client,
+        reference: &str,
+        _dates: RangeInclusive<Date>,
+    ) -> Result<ListDays> {
+        list_days(client, reference).await
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use reqwest::Client;
+    use time::macros::date;
+
+    use crate::client::fixture;
+
+    #[tokio::test]
+    async fn list_menus() {
+        let menus = super::list_menus(&fixture("sabis")).await.unwrap();
+
+        let slugs = menus
+            .iter()
+            .map(|m| m.supplier_reference.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(slugs, ["carnegie", "kista-science-tower", "solna-strand"]);
+        assert_eq!(menus[0].title, "Carnegie");
+    }
+
+    #[test]
+    fn restaurant_slug() {
+        assert_eq!(
+            super::restaurant_slug("/restauranger-cafeer/vara-foretagsrestauranger/carnegie/"),
+            Some("carnegie")
+        );
+        assert_eq!(
+            super::restaurant_slug(
+                "https://www.sabis.se/restauranger-cafeer/vara-foretagsrestauranger/carnegie"
+            ),
+            Some("carnegie")
+        );
+        assert!(
+            super::restaurant_slug("/restauranger-cafeer/vara-foretagsrestauranger/").is_none()
+        );
+        assert!(super::restaurant_slug("/restauranger-cafeer/").is_none());
+    }
 
     #[tokio::test]
     async fn carnegie() {
-        let res = super::list_days(&Client::new(), "carnegie").await.unwrap();
+        let res = super::list_days(&fixture("sabis"), "carnegie")
+            .await
+            .unwrap();
 
         assert_eq!(res.days.len(), 5);
+        assert_eq!(res.days[0].date, date!(2023 - 02 - 06));
     }
 }
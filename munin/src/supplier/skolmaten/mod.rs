@@ -1,10 +1,11 @@
-use std::{iter, ops::RangeInclusive};
+use std::{iter, ops::RangeInclusive, time::Duration};
 
+use async_trait::async_trait;
 use futures::{
     stream::{self, StreamExt},
     TryFutureExt, TryStreamExt,
 };
-use reqwest::{Client, StatusCode};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use stor::{
     meal::sanitize_meal_value,
@@ -13,9 +14,12 @@ use stor::{
 use time::{Date, Month};
 use tracing::{error, instrument};
 
-use crate::{Error, Result};
+use crate::{
+    client::{Client, RateLimit},
+    Error, Result,
+};
 
-use super::ListDays;
+use super::{Canary, ListDays};
 
 /// Maximum number of concurrent HTTP requests when crawling. For comparison,
 /// Firefox allows 7 concurrent requests. There is virtually no improvement for
@@ -344,8 +348,55 @@ pub async fn list_days(
     Ok(ListDays { menu, days })
 }
 
+pub struct Skolmaten;
+
+fn parse_station(reference: &str) -> Result<u64> {
+    reference
+        .parse()
+        .map_err(|_| Error::InvalidReference.into())
+}
+
+#[async_trait]
+impl super::Supplier for Skolmaten {
+    fn id(&self) -> Supplier {
+        Supplier::Skolmaten
+    }
+
+    fn hosts(&self) -> &'static [&'static str] {
+        &["https://skolmaten.se"]
+    }
+
+    fn canary(&self) -> Canary {
+        Canary {
+            reference: "4889403990212608",
+            classes: &[],
+        }
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        RateLimit::new(CONCURRENT_REQUESTS, Duration::ZERO)
+    }
+
+    fn validate_reference(&self, reference: &str) -> Result<()> {
+        parse_station(reference).map(|_| ())
+    }
+
+    async fn list_menus(&self, client: &Client) -> Result<Vec<stor::Menu>> {
+        list_menus(client).await
+    }
+
+    async fn list_days(
+        &self,
+        client: &Client,
+        reference: &str,
+        dates: RangeInclusive<Date>,
+    ) -> Result<ListDays> {
+        list_days(client, parse_station(reference)?, dates).await
+    }
+}
+
 #[instrument(level = "debug", err)]
-async fn fetch(client: &Client, path: &str) -> reqwest::Result<reqwest::Response> {
+async fn fetch(client: &Client, path: &str) -> Result<reqwest::Response, crate::client::Error> {
     let url = format!("https://skolmaten.se/api/4/{path}");
 
     client
@@ -360,33 +411,39 @@ async fn fetch(client: &Client, path: &str) -> reqwest::Result<reqwest::Response
 
 #[cfg(test)]
 mod tests {
-    use reqwest::Client;
-    use time::{macros::date, Duration, OffsetDateTime};
-    use time_tz::OffsetDateTimeExt;
+    use time::macros::date;
 
-    use crate::supplier::ListDays;
+    use crate::{client::fixture, supplier::ListDays};
 
     use super::WeekSpan;
 
     #[tokio::test]
     async fn list_menus() {
-        let menus = super::list_menus(&Client::new()).await.unwrap();
+        let menus = super::list_menus(&fixture("skolmaten")).await.unwrap();
 
-        assert!(menus.len() > 5000);
+        assert_eq!(menus.len(), 3);
+        assert!(menus
+            .iter()
+            .any(|m| m.title == "Engelbrektsskolan, Stockholm" && m.location.is_some()));
     }
 
     #[tokio::test]
     async fn list_days() {
-        let first_day = OffsetDateTime::now_utc().to_timezone(crate::TZ).date();
-        let last_day = first_day + Duration::weeks(2);
-
-        let ListDays { menu, days } =
-            super::list_days(&Client::new(), 4889403990212608, first_day..=last_day)
-                .await
-                .unwrap();
+        let ListDays { menu, days } = super::list_days(
+            &fixture("skolmaten"),
+            4889403990212608,
+            date!(2023 - 02 - 06)..=date!(2023 - 02 - 19),
+        )
+        .await
+        .unwrap();
 
         assert!(!menu.is_empty());
-        assert!(!days.is_empty());
+        assert_eq!(days.len(), 10);
+        assert_eq!(days[0].date, date!(2023 - 02 - 06));
+        assert_eq!(
+            days[0].meals,
+            ["Köttbullar med potatismos", "Falafel med potatismos"]
+        );
     }
 
     #[test]
@@ -2,35 +2,39 @@ use stor::menu::Supplier;
 
 use crate::mashie::mashie_impl;
 
-mashie_impl!("https://sodexo.mashie.com", Supplier::Sodexo);
+mashie_impl!(
+    Sodexo,
+    "https://sodexo.mashie.com",
+    Supplier::Sodexo,
+    "312dd0ae-3ebd-49d9-870e-abeb008c0e4b"
+);
 
 #[cfg(test)]
 mod tests {
-    use reqwest::Client;
-    use time::{Duration, OffsetDateTime};
+    use time::macros::date;
 
-    use crate::supplier::ListDays;
+    use crate::{client::fixture, supplier::ListDays};
 
     #[tokio::test]
     async fn list_menus() {
-        let menus = super::list_menus(&Client::new()).await.unwrap();
-        assert!(menus.len() > 100);
+        let menus = super::list_menus(&fixture("sodexo")).await.unwrap();
+        assert_eq!(menus.len(), 3);
     }
 
     #[tokio::test]
     async fn list_days() {
-        let first = OffsetDateTime::now_utc().date();
-        let last = first + Duration::days(365);
+        let first = date!(2023 - 02 - 07);
+        let last = date!(2023 - 02 - 20);
 
         let ListDays { days, .. } = super::list_days(
-            &Client::new(),
+            &fixture("sodexo"),
             "312dd0ae-3ebd-49d9-870e-abeb008c0e4b",
             first..=last,
         )
         .await
         .unwrap();
 
-        assert!(days.len() > 5);
+        assert_eq!(days.len(), 7);
 
         for day in days {
             assert!(day.date >= first);
@@ -0,0 +1,16 @@
+CREATE TYPE failure_kind AS ENUM ('transient', 'permanent');
+
+ALTER TABLE
+  menus
+ADD
+  COLUMN failure_kind failure_kind,
+ADD
+  COLUMN failure_reason TEXT,
+ADD
+  COLUMN failure_url TEXT,
+ADD
+  COLUMN failure_status INT,
+ADD
+  COLUMN failure_excerpt TEXT,
+ADD
+  COLUMN failed_at TIMESTAMPTZ;
@@ -0,0 +1,20 @@
+-- meals that were present before this migration get its time as first_seen,
+-- which is the best we know
+ALTER TABLE
+  meals
+ADD
+  COLUMN first_seen TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+ADD
+  COLUMN last_seen TIMESTAMPTZ NOT NULL DEFAULT NOW();
+
+-- meals that have been removed from a menu, kept to tell what a menu looked
+-- like at any given time
+CREATE TABLE meal_history (
+  menu_id UUID NOT NULL REFERENCES menus(id) ON DELETE CASCADE,
+  date DATE NOT NULL,
+  meal TEXT NOT NULL,
+  first_seen TIMESTAMPTZ NOT NULL,
+  last_seen TIMESTAMPTZ NOT NULL,
+  removed_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY (menu_id, date, meal, first_seen)
+);
@@ -0,0 +1,6 @@
+ALTER TABLE
+  menus
+ADD
+  COLUMN leased_by UUID,
+ADD
+  COLUMN lease_expires_at TIMESTAMPTZ;
@@ -0,0 +1,6 @@
+ALTER TABLE
+  menus
+ADD
+  COLUMN check_interval INTERVAL,
+ADD
+  COLUMN last_changed_at TIMESTAMPTZ;
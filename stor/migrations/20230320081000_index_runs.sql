@@ -0,0 +1,23 @@
+CREATE TABLE index_runs (
+  id UUID PRIMARY KEY,
+  started_at TIMESTAMPTZ NOT NULL,
+  finished_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX index_runs_started_at ON index_runs (started_at);
+
+-- how the menus of each supplier fared in a run
+CREATE TABLE index_run_suppliers (
+  run_id UUID NOT NULL REFERENCES index_runs(id) ON DELETE CASCADE,
+  supplier supplier NOT NULL,
+  total INT NOT NULL,
+  successful INT NOT NULL,
+  transient_failures INT NOT NULL,
+  permanent_failures INT NOT NULL,
+  failure_reasons TEXT[] NOT NULL,
+  -- the number of meals that were scraped, changed or not
+  meals BIGINT NOT NULL,
+  meals_inserted BIGINT NOT NULL,
+  meals_deleted BIGINT NOT NULL,
+  PRIMARY KEY (run_id, supplier)
+);
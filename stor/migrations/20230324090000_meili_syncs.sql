@@ -0,0 +1,6 @@
+-- when each meilisearch index was last synced, so that only the documents
+-- that changed since then need to be uploaded
+CREATE TABLE meili_syncs (
+  index_uid TEXT PRIMARY KEY,
+  started_at TIMESTAMPTZ NOT NULL
+);
@@ -1,8 +1,10 @@
+pub mod config;
 #[cfg(feature = "db")]
 pub mod db;
 pub mod meal;
 pub mod menu;
 pub mod review;
+pub mod run;
 
 pub use meal::Meal;
 pub use menu::Menu;
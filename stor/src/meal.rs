@@ -1,13 +1,65 @@
+#[cfg(feature = "db")]
+use std::ops::RangeInclusive;
+
 use serde::{Deserialize, Serialize};
-use time::Date;
+use time::{Date, OffsetDateTime};
 use uuid::Uuid;
 
+#[cfg(feature = "db")]
+use crate::Day;
+
 #[derive(Debug, Serialize, Deserialize)]
 #[cfg_attr(feature = "db", derive(sqlx::FromRow))]
 pub struct Meal {
     pub menu_id: Uuid,
     pub meal: String,
     pub date: Date,
+    /// When the meal first appeared on the menu.
+    #[serde(with = "time::serde::rfc3339")]
+    pub first_seen: OffsetDateTime,
+    /// When the meal was last seen on the menu.
+    #[serde(with = "time::serde::rfc3339")]
+    pub last_seen: OffsetDateTime,
+}
+
+/// The days of a menu as they were known at `at`: every meal that had
+/// appeared by then and was yet to be removed. Days without any meals are
+/// left out.
+#[cfg(feature = "db")]
+pub async fn days_at<'c>(
+    executor: impl sqlx::PgExecutor<'c>,
+    menu_id: Uuid,
+    dates: RangeInclusive<Date>,
+    at: OffsetDateTime,
+) -> sqlx::Result<Vec<Day>> {
+    let meals = sqlx::query_as::<_, (Date, String)>(
+        r#"
+            SELECT date, meal FROM meals
+                WHERE menu_id = $1 AND date BETWEEN $2 AND $3 AND first_seen <= $4
+            UNION
+            SELECT date, meal FROM meal_history
+                WHERE menu_id = $1 AND date BETWEEN $2 AND $3 AND first_seen <= $4
+                    AND removed_at > $4
+            ORDER BY date, meal
+        "#,
+    )
+    .bind(menu_id)
+    .bind(dates.start())
+    .bind(dates.end())
+    .bind(at)
+    .fetch_all(executor)
+    .await?;
+
+    let mut days: Vec<Day> = Vec::new();
+
+    for (date, meal) in meals {
+        match days.last_mut() {
+            Some(day) if day.date == date => day.meals.push(meal),
+            _ => days.push(Day::new(date, vec![meal])),
+        }
+    }
+
+    Ok(days)
 }
 
 pub fn sanitize_meal_value(s: &str) -> Option<String> {
@@ -12,7 +12,19 @@ pub const UUID_NAMESPACE: Uuid = Uuid::from_bytes([
 ]);
 
 #[derive(
-    Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, EnumIter, EnumString, strum::Display,
+    Debug,
+    Clone,
+    Copy,
+    Serialize,
+    Deserialize,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    EnumIter,
+    EnumString,
+    strum::Display,
 )]
 #[serde(rename_all = "lowercase")]
 #[strum(serialize_all = "lowercase")]
@@ -30,6 +42,35 @@ pub enum Supplier {
     Matilda,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, EnumString, strum::Display)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+#[cfg_attr(
+    feature = "db",
+    derive(sqlx::Type),
+    sqlx(type_name = "failure_kind", rename_all = "lowercase")
+)]
+pub enum FailureKind {
+    /// Likely to go away by itself, e.g. a timeout or a 5xx response.
+    Transient,
+    /// Will not go away until the supplier (or we) change something, e.g. a
+    /// 404 response or markup that cannot be parsed.
+    Permanent,
+}
+
+/// Why the last attempt to refresh a menu failed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub reason: String,
+    pub url: Option<String>,
+    pub status: Option<u16>,
+    /// The beginning of the response body, if any.
+    pub excerpt: Option<String>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub failed_at: OffsetDateTime,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Menu {
     pub id: Uuid,
@@ -43,6 +84,10 @@ pub struct Menu {
     #[serde(with = "time::serde::rfc3339::option")]
     pub checked_at: Option<OffsetDateTime>,
     pub consecutive_failures: i32,
+    /// Not serialized, as the excerpt and URLs are for us and not for
+    /// whoever is reading the menus.
+    #[serde(skip_serializing)]
+    pub last_failure: Option<Failure>,
 }
 
 /// A patch to a menu.
@@ -98,6 +143,7 @@ impl Menu {
             created_at: None,
             checked_at: None,
             consecutive_failures: 0,
+            last_failure: None,
         }
     }
 
@@ -138,6 +184,20 @@ impl FromRow<'_, PgRow> for Menu {
             .transpose()
             .map_err(|e| sqlx::Error::Decode(e.into()))?;
 
+        let last_failure = match row.try_get::<Option<FailureKind>, _>("failure_kind")? {
+            Some(kind) => Some(Failure {
+                kind,
+                reason: row.try_get("failure_reason")?,
+                url: row.try_get("failure_url")?,
+                status: row
+                    .try_get::<Option<i32>, _>("failure_status")?
+                    .and_then(|s| u16::try_from(s).ok()),
+                excerpt: row.try_get("failure_excerpt")?,
+                failed_at: row.try_get("failed_at")?,
+            }),
+            None => None,
+        };
+
         Ok(Self {
             id: row.try_get("id")?,
             title: row.try_get("title")?,
@@ -148,6 +208,7 @@ impl FromRow<'_, PgRow> for Menu {
             created_at: row.try_get("created_at")?,
             checked_at: row.try_get("checked_at")?,
             consecutive_failures: row.try_get("consecutive_failures")?,
+            last_failure,
         })
     }
 }
@@ -158,7 +219,7 @@ mod tests {
 
     use crate::menu::Supplier;
 
-    use super::Menu;
+    use super::{FailureKind, Menu};
 
     #[cfg(feature = "db")]
     #[sqlx::test]
@@ -188,6 +249,26 @@ mod tests {
         assert_eq!(menu.title, title);
         assert_eq!(menu.supplier, supplier);
         assert_eq!(menu.supplier_reference, supplier_reference);
+        assert!(menu.last_failure.is_none());
+
+        sqlx::query(
+            "UPDATE menus SET failure_kind = 'transient', failure_reason = 'timed out', failed_at = now()",
+        )
+        .execute(&mut conn)
+        .await?;
+
+        let menu = sqlx::query_as::<_, Menu>("SELECT * FROM menus")
+            .fetch_one(&mut conn)
+            .await?;
+        assert!(serde_json::to_value(&menu)
+            .unwrap()
+            .get("last_failure")
+            .is_none());
+        let failure = menu.last_failure.unwrap();
+
+        assert_eq!(failure.kind, FailureKind::Transient);
+        assert_eq!(failure.reason, "timed out");
+        assert!(failure.status.is_none());
 
         Ok(())
     }
@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "db")]
+use sqlx::{FromRow, Row};
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::menu::{FailureKind, Supplier};
+
+/// The most distinct failure reasons that are kept for each supplier in a run.
+pub const MAX_FAILURE_REASONS: usize = 20;
+
+/// A single run of the indexer, i.e. one refresh of the expired menus.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexRun {
+    pub id: Uuid,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339")]
+    pub finished_at: OffsetDateTime,
+    pub suppliers: Vec<SupplierRun>,
+}
+
+/// How the menus of a supplier fared in a run.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[cfg_attr(feature = "db", derive(sqlx::FromRow))]
+pub struct SupplierRun {
+    pub supplier: Supplier,
+    /// The number of menus that were checked.
+    pub total: i32,
+    pub successful: i32,
+    pub transient_failures: i32,
+    pub permanent_failures: i32,
+    /// Distinct failure reasons, at most [`MAX_FAILURE_REASONS`].
+    pub failure_reasons: Vec<String>,
+    /// The number of meals on the menus that were refreshed.
+    pub meals: i64,
+    pub meals_inserted: i64,
+    pub meals_deleted: i64,
+}
+
+impl SupplierRun {
+    pub fn new(supplier: Supplier) -> Self {
+        Self {
+            supplier,
+            total: 0,
+            successful: 0,
+            transient_failures: 0,
+            permanent_failures: 0,
+            failure_reasons: Vec::new(),
+            meals: 0,
+            meals_inserted: 0,
+            meals_deleted: 0,
+        }
+    }
+
+    /// Count a menu that was refreshed successfully.
+    pub fn success(&mut self, meals: u64, inserted: u64, deleted: u64) {
+        self.total += 1;
+        self.successful += 1;
+        self.meals += meals as i64;
+        self.meals_inserted += inserted as i64;
+        self.meals_deleted += deleted as i64;
+    }
+
+    /// Count a menu that could not be refreshed.
+    pub fn failure(&mut self, kind: FailureKind, reason: &str) {
+        self.total += 1;
+
+        match kind {
+            FailureKind::Transient => self.transient_failures += 1,
+            FailureKind::Permanent => self.permanent_failures += 1,
+        }
+
+        self.add_failure_reason(reason);
+    }
+
+    /// Add the results of `other`, e.g. of a later run.
+    pub fn merge(&mut self, other: &Self) {
+        self.total += other.total;
+        self.successful += other.successful;
+        self.transient_failures += other.transient_failures;
+        self.permanent_failures += other.permanent_failures;
+        self.meals += other.meals;
+        self.meals_inserted += other.meals_inserted;
+        self.meals_deleted += other.meals_deleted;
+
+        for reason in &other.failure_reasons {
+            self.add_failure_reason(reason);
+        }
+    }
+
+    fn add_failure_reason(&mut self, reason: &str) {
+        if self.failure_reasons.len() < MAX_FAILURE_REASONS
+            && !self.failure_reasons.iter().any(|r| r == reason)
+        {
+            self.failure_reasons.push(reason.to_owned());
+        }
+    }
+}
+
+impl IndexRun {
+    pub fn new(started_at: OffsetDateTime) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            started_at,
+            finished_at: started_at,
+            suppliers: Vec::new(),
+        }
+    }
+
+    pub fn duration(&self) -> time::Duration {
+        self.finished_at - self.started_at
+    }
+
+    /// The number of menus that were checked, across all suppliers.
+    pub fn total(&self) -> i32 {
+        self.suppliers.iter().map(|s| s.total).sum()
+    }
+
+    /// The number of menus that were refreshed, across all suppliers.
+    pub fn successful(&self) -> i32 {
+        self.suppliers.iter().map(|s| s.successful).sum()
+    }
+
+    /// Add the results of `other`, a later run, to this one.
+    pub fn merge(&mut self, other: &Self) {
+        self.finished_at = self.finished_at.max(other.finished_at);
+
+        for s in &other.suppliers {
+            self.supplier_mut(s.supplier).merge(s);
+        }
+    }
+
+    /// The results of `supplier`, added if not present.
+    pub fn supplier_mut(&mut self, supplier: Supplier) -> &mut SupplierRun {
+        let i = match self.suppliers.iter().position(|s| s.supplier == supplier) {
+            Some(i) => i,
+            None => {
+                self.suppliers.push(SupplierRun::new(supplier));
+                self.suppliers.len() - 1
+            }
+        };
+
+        &mut self.suppliers[i]
+    }
+}
+
+#[cfg(feature = "db")]
+impl IndexRun {
+    pub async fn insert(&self, conn: &mut sqlx::PgConnection) -> sqlx::Result<()> {
+        use sqlx::Connection;
+
+        let mut txn = conn.begin().await?;
+
+        sqlx::query("INSERT INTO index_runs (id, started_at, finished_at) VALUES ($1, $2, $3)")
+            .bind(self.id)
+            .bind(self.started_at)
+            .bind(self.finished_at)
+            .execute(&mut txn)
+            .await?;
+
+        for s in &self.suppliers {
+            sqlx::query(
+                r#"
+                    INSERT INTO index_run_suppliers (
+                        run_id, supplier, total, successful, transient_failures,
+                        permanent_failures, failure_reasons, meals, meals_inserted,
+                        meals_deleted
+                    ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                "#,
+            )
+            .bind(self.id)
+            .bind(s.supplier)
+            .bind(s.total)
+            .bind(s.successful)
+            .bind(s.transient_failures)
+            .bind(s.permanent_failures)
+            .bind(&s.failure_reasons)
+            .bind(s.meals)
+            .bind(s.meals_inserted)
+            .bind(s.meals_deleted)
+            .execute(&mut txn)
+            .await?;
+        }
+
+        txn.commit().await
+    }
+
+    /// The `limit` most recent runs, newest first.
+    pub async fn latest(conn: &mut sqlx::PgConnection, limit: i64) -> sqlx::Result<Vec<Self>> {
+        let runs = sqlx::query_as::<_, (Uuid, OffsetDateTime, OffsetDateTime)>(
+            "SELECT id, started_at, finished_at FROM index_runs ORDER BY started_at DESC LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Self::with_suppliers(conn, runs).await
+    }
+
+    /// The runs that started from `from` up to and including `to`, newest
+    /// first.
+    pub async fn between(
+        conn: &mut sqlx::PgConnection,
+        from: OffsetDateTime,
+        to: OffsetDateTime,
+    ) -> sqlx::Result<Vec<Self>> {
+        let runs = sqlx::query_as::<_, (Uuid, OffsetDateTime, OffsetDateTime)>(
+            r#"
+                SELECT id, started_at, finished_at FROM index_runs
+                WHERE started_at >= $1 AND started_at <= $2
+                ORDER BY started_at DESC
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Self::with_suppliers(conn, runs).await
+    }
+
+    async fn with_suppliers(
+        conn: &mut sqlx::PgConnection,
+        runs: Vec<(Uuid, OffsetDateTime, OffsetDateTime)>,
+    ) -> sqlx::Result<Vec<Self>> {
+        let mut runs = runs
+            .into_iter()
+            .map(|(id, started_at, finished_at)| Self {
+                id,
+                started_at,
+                finished_at,
+                suppliers: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let ids = runs.iter().map(|r| r.id).collect::<Vec<_>>();
+
+        let rows = sqlx::query(
+            "SELECT * FROM index_run_suppliers WHERE run_id = ANY($1) ORDER BY supplier",
+        )
+        .bind(&ids)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        for row in rows {
+            let run_id: Uuid = row.try_get("run_id")?;
+
+            if let Some(run) = runs.iter_mut().find(|r| r.id == run_id) {
+                run.suppliers.push(SupplierRun::from_row(&row)?);
+            }
+        }
+
+        Ok(runs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn supplier_run() {
+        let mut run = IndexRun::new(OffsetDateTime::now_utc());
+
+        run.supplier_mut(Supplier::Sodexo).success(8, 3, 1);
+        run.supplier_mut(Supplier::Sodexo)
+            .failure(FailureKind::Transient, "timed out");
+        run.supplier_mut(Supplier::Sodexo)
+            .failure(FailureKind::Transient, "timed out");
+        run.supplier_mut(Supplier::Mpi)
+            .failure(FailureKind::Permanent, "404 Not Found");
+
+        assert_eq!(run.total(), 4);
+        assert_eq!(run.suppliers.len(), 2);
+
+        let sodexo = &run.suppliers[0];
+        assert_eq!((sodexo.total, sodexo.successful), (3, 1));
+        assert_eq!(sodexo.transient_failures, 2);
+        assert_eq!(sodexo.failure_reasons, ["timed out"]);
+        assert_eq!(sodexo.meals, 8);
+        assert_eq!((sodexo.meals_inserted, sodexo.meals_deleted), (3, 1));
+
+        let mut later = IndexRun::new(run.started_at + time::Duration::minutes(1));
+        later.finished_at = later.started_at + time::Duration::minutes(1);
+        later
+            .supplier_mut(Supplier::Sodexo)
+            .failure(FailureKind::Permanent, "404 Not Found");
+        later.supplier_mut(Supplier::Kleins).success(5, 5, 0);
+        run.merge(&later);
+
+        assert_eq!(run.total(), 6);
+        assert_eq!(run.finished_at, later.finished_at);
+
+        let sodexo = &run.suppliers[0];
+        assert_eq!((sodexo.total, sodexo.permanent_failures), (4, 1));
+        assert_eq!(sodexo.failure_reasons, ["timed out", "404 Not Found"]);
+    }
+
+    #[cfg(feature = "db")]
+    #[sqlx::test]
+    async fn insert_and_fetch(pool: sqlx::PgPool) -> sqlx::Result<()> {
+        let mut conn = pool.acquire().await?;
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).unwrap();
+
+        let mut older = IndexRun::new(now - time::Duration::hours(1));
+        older.finished_at = older.started_at + time::Duration::minutes(2);
+        older.supplier_mut(Supplier::Skolmaten).success(20, 10, 0);
+        older.insert(&mut conn).await?;
+
+        let mut newer = IndexRun::new(now);
+        newer.finished_at = now + time::Duration::minutes(1);
+        newer
+            .supplier_mut(Supplier::Mpi)
+            .failure(FailureKind::Permanent, "404 Not Found");
+        newer.supplier_mut(Supplier::Skolmaten).success(20, 2, 1);
+        newer.insert(&mut conn).await?;
+
+        let runs = IndexRun::latest(&mut conn, 10).await?;
+        assert_eq!(
+            runs.iter().map(|r| r.id).collect::<Vec<_>>(),
+            [newer.id, older.id]
+        );
+        assert_eq!(runs[0].duration(), time::Duration::minutes(1));
+        assert_eq!(runs[0].suppliers.len(), 2);
+        assert_eq!(runs[1].suppliers, older.suppliers);
+
+        assert_eq!(IndexRun::latest(&mut conn, 1).await?.len(), 1);
+
+        let runs = IndexRun::between(&mut conn, now - time::Duration::minutes(30), now).await?;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].id, newer.id);
+        assert_eq!(runs[0].suppliers.len(), 2);
+
+        Ok(())
+    }
+}